@@ -0,0 +1,546 @@
+use crate::error::*;
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, Read},
+    os::unix::fs::{symlink, PermissionsExt},
+    path::{Component, Path, PathBuf},
+};
+use tar::{Archive, EntryType};
+use zip::ZipArchive;
+
+// Archive formats by the magic bytes their files start with
+const MAGIC: [(&[u8], Format); 5] = [
+    (&[0x1f, 0x8b], Format::TarGz),
+    (&[0xfd, b'7', b'z', b'X', b'Z', 0x00], Format::TarXz),
+    (b"BZh", Format::TarBz2),
+    (&[0x28, 0xb5, 0x2f, 0xfd], Format::TarZst),
+    (b"PK\x03\x04", Format::Zip),
+];
+
+// Archive formats by file name, for files that can't be read yet
+const EXTENSIONS: [(&str, Format); 10] = [
+    (".tar.gz", Format::TarGz),
+    (".tgz", Format::TarGz),
+    (".tar.xz", Format::TarXz),
+    (".txz", Format::TarXz),
+    (".tar.bz2", Format::TarBz2),
+    (".tbz2", Format::TarBz2),
+    (".tar.zst", Format::TarZst),
+    (".tzst", Format::TarZst),
+    (".tar", Format::Tar),
+    (".zip", Format::Zip),
+];
+
+// Where a tar header says "ustar"
+const USTAR_OFFSET: usize = 257;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Tar,
+    TarGz,
+    TarXz,
+    TarBz2,
+    TarZst,
+    Zip,
+}
+
+impl Format {
+    /// Detects the format from the file's first bytes, or from its name when
+    /// it can't be read (e.g. it isn't downloaded yet in a dry run)
+    pub fn detect(path: &str) -> Result<Self> {
+        let mut head = Vec::new();
+        if let Ok(file) = File::open(path) {
+            file.take(USTAR_OFFSET as u64 + 5).read_to_end(&mut head)?;
+        }
+
+        let by_magic = MAGIC
+            .iter()
+            .find(|(magic, _)| head.starts_with(magic))
+            .map(|(_, format)| *format);
+        let is_tar = head.get(USTAR_OFFSET..) == Some(b"ustar".as_slice());
+
+        by_magic
+            .or(is_tar.then_some(Format::Tar))
+            .or_else(|| Self::from_name(path))
+            .ok_or_else(|| CrossDevError::UnknownArchiveFormat(path.to_string()))
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        let name = name.to_ascii_lowercase();
+        EXTENSIONS
+            .iter()
+            .find(|(extension, _)| name.ends_with(extension))
+            .map(|(_, format)| *format)
+    }
+}
+
+/// `filename` without its archive extension, e.g. `qemu-9.2.0` for
+/// `qemu-9.2.0.tar.xz`
+pub fn archive_stem(filename: &str) -> &str {
+    let lower = filename.to_ascii_lowercase();
+
+    EXTENSIONS
+        .iter()
+        .find(|(extension, _)| lower.ends_with(extension))
+        .map_or(filename, |(extension, _)| {
+            &filename[..filename.len() - extension.len()]
+        })
+}
+
+/// Extracts `archive` into `dest`, dropping the first `strip_components`
+/// directories of every entry. The archive is unpacked next to `dest` first
+/// and only moved into place once complete.
+pub fn extract(archive: &str, dest: &str, strip_components: usize) -> Result<()> {
+    let partial = PathBuf::from(format!("{dest}.partial"));
+    if partial.exists() {
+        fs::remove_dir_all(&partial)?;
+    }
+    fs::create_dir_all(&partial)?;
+
+    let file = BufReader::new(File::open(archive)?);
+    match Format::detect(archive)? {
+        Format::Zip => extract_zip(file, &partial, strip_components)?,
+        format => extract_tar(decoder(format, file)?, &partial, strip_components)?,
+    }
+
+    // An empty directory may have been made for the sources beforehand
+    if Path::new(dest).is_dir() {
+        fs::remove_dir(dest)?;
+    }
+    fs::rename(&partial, dest)?;
+
+    Ok(())
+}
+
+/// The files `extract` would create, relative to `dest`
+pub fn list(archive: &str, strip_components: usize) -> Result<Vec<PathBuf>> {
+    let file = BufReader::new(File::open(archive)?);
+    let mut files = Vec::new();
+
+    match Format::detect(archive)? {
+        Format::Zip => {
+            let mut archive = ZipArchive::new(file)?;
+            for index in 0..archive.len() {
+                let entry = archive.by_index(index)?;
+                let Some(path) = entry.enclosed_name() else {
+                    return Err(CrossDevError::BadArchiveEntry(entry.name().to_string()));
+                };
+                if entry.is_dir() {
+                    continue;
+                }
+                files.extend(strip(&path, strip_components)?);
+            }
+        }
+        format => {
+            let mut archive = Archive::new(decoder(format, file)?);
+            for entry in archive.entries()? {
+                let entry = entry?;
+                if entry.header().entry_type() == EntryType::Directory {
+                    continue;
+                }
+                files.extend(strip(&entry.path()?, strip_components)?);
+            }
+        }
+    }
+
+    Ok(files)
+}
+
+// Decompresses a tar archive of `format`
+fn decoder(format: Format, file: BufReader<File>) -> Result<Box<dyn Read>> {
+    Ok(match format {
+        Format::TarGz => Box::new(flate2::read::GzDecoder::new(file)),
+        Format::TarXz => Box::new(xz2::read::XzDecoder::new(file)),
+        Format::TarBz2 => Box::new(bzip2::read::BzDecoder::new(file)),
+        Format::TarZst => Box::new(zstd::Decoder::with_buffer(file)?),
+        Format::Tar | Format::Zip => Box::new(file),
+    })
+}
+
+fn extract_tar(reader: impl Read, dest: &Path, strip_components: usize) -> Result<()> {
+    let mut archive = Archive::new(reader);
+    archive.set_preserve_mtime(true);
+    archive.set_preserve_permissions(true);
+    let root = dest.canonicalize()?;
+    let mut directories = Vec::new();
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        let Some(relative) = strip(&path, strip_components)? else {
+            continue;
+        };
+        let target = dest.join(&relative);
+        let bad_entry = || CrossDevError::BadArchiveEntry(path.display().to_string());
+        if !make_parent(&root, &target)? {
+            return Err(bad_entry());
+        }
+
+        match entry.header().entry_type() {
+            // Hard links point at another entry, which moved along with it
+            EntryType::Link => {
+                let Some(link) = entry.link_name()? else {
+                    continue;
+                };
+                let Some(original) = strip(&link, strip_components)? else {
+                    continue;
+                };
+                let original = dest.join(original);
+                if !is_inside(&root, &original) {
+                    return Err(bad_entry());
+                }
+                remove_symlink(&target)?;
+                fs::hard_link(original, &target)?;
+            }
+            // Their permissions are set last, so read-only ones can still be filled
+            EntryType::Directory => {
+                fs::create_dir_all(&target)?;
+                if !is_inside(&root, &target) {
+                    return Err(bad_entry());
+                }
+                directories.push((target, entry.header().mode()?));
+            }
+            _ => {
+                remove_symlink(&target)?;
+                entry.unpack(&target)?;
+            }
+        }
+    }
+
+    for (target, mode) in directories.into_iter().rev() {
+        fs::set_permissions(target, fs::Permissions::from_mode(mode & 0o7777))?;
+    }
+
+    Ok(())
+}
+
+fn extract_zip(file: BufReader<File>, dest: &Path, strip_components: usize) -> Result<()> {
+    let mut archive = ZipArchive::new(file)?;
+    let root = dest.canonicalize()?;
+
+    for index in 0..archive.len() {
+        let mut entry = archive.by_index(index)?;
+        let Some(path) = entry.enclosed_name() else {
+            return Err(CrossDevError::BadArchiveEntry(entry.name().to_string()));
+        };
+        let Some(relative) = strip(&path, strip_components)? else {
+            continue;
+        };
+        let target = dest.join(relative);
+        let bad_entry = || CrossDevError::BadArchiveEntry(path.display().to_string());
+        if !make_parent(&root, &target)? {
+            return Err(bad_entry());
+        }
+
+        if entry.is_dir() {
+            fs::create_dir_all(&target)?;
+            if !is_inside(&root, &target) {
+                return Err(bad_entry());
+            }
+            continue;
+        }
+        remove_symlink(&target)?;
+
+        let mode = entry.unix_mode();
+        if entry.is_symlink() {
+            let mut link = String::new();
+            entry.read_to_string(&mut link)?;
+            symlink(link, &target)?;
+            continue;
+        }
+
+        io::copy(&mut entry, &mut File::create(&target)?)?;
+        if let Some(mode) = mode {
+            fs::set_permissions(&target, fs::Permissions::from_mode(mode & 0o7777))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Creates the directories `target` goes into, unless a symlink unpacked
+/// earlier leads them out of `root` (a canonical path). Returns whether the
+/// parent of `target` is safe to unpack into.
+pub(crate) fn make_parent(root: &Path, target: &Path) -> Result<bool> {
+    let Some(parent) = target.parent() else {
+        return Ok(true);
+    };
+
+    // Whatever doesn't exist yet can't be a symlink
+    let mut existing = parent;
+    while existing.symlink_metadata().is_err() {
+        match existing.parent() {
+            Some(up) => existing = up,
+            None => break,
+        }
+    }
+    if !is_inside(root, existing) {
+        return Ok(false);
+    }
+    fs::create_dir_all(parent)?;
+
+    Ok(true)
+}
+
+/// Whether `path`, with every symlink resolved, is inside `root`
+pub(crate) fn is_inside(root: &Path, path: &Path) -> bool {
+    path.canonicalize().is_ok_and(|path| path.starts_with(root))
+}
+
+/// Makes room for an entry in place of a symlink an earlier one left, so it
+/// isn't written through it
+pub(crate) fn remove_symlink(target: &Path) -> Result<()> {
+    if target
+        .symlink_metadata()
+        .is_ok_and(|metadata| metadata.file_type().is_symlink())
+    {
+        fs::remove_file(target)?;
+    }
+
+    Ok(())
+}
+
+// `path` without its first `count` components, `None` for what is stripped
+// entirely; anything escaping the destination is refused
+fn strip(path: &Path, count: usize) -> Result<Option<PathBuf>> {
+    let mut components = Vec::new();
+
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => components.push(part),
+            Component::CurDir => {}
+            _ => return Err(CrossDevError::BadArchiveEntry(path.display().to_string())),
+        }
+    }
+
+    Ok(if components.len() > count {
+        Some(components[count..].iter().collect())
+    } else {
+        None
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::io::Write;
+    use tar::{Builder, Header};
+    use zip::{write::SimpleFileOptions, ZipWriter};
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("crossdev-archive-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        dir
+    }
+
+    // What a tar entry is
+    pub(crate) enum Entry<'a> {
+        File(&'a [u8]),
+        Symlink(&'a str),
+        HardLink(&'a str),
+    }
+
+    // The name is written as is, so it may be anything a hostile archive holds
+    pub(crate) fn write_tar(path: &Path, entries: &[(&str, Entry)]) {
+        let mut builder = Builder::new(File::create(path).unwrap());
+
+        for (name, entry) in entries {
+            let mut header = Header::new_gnu();
+            header.as_old_mut().name[..name.len()].copy_from_slice(name.as_bytes());
+            header.set_mode(0o644);
+            let data: &[u8] = match entry {
+                Entry::File(data) => {
+                    header.set_entry_type(EntryType::Regular);
+                    data
+                }
+                Entry::Symlink(link) | Entry::HardLink(link) => {
+                    header.set_entry_type(match entry {
+                        Entry::Symlink(_) => EntryType::Symlink,
+                        _ => EntryType::Link,
+                    });
+                    header.as_old_mut().linkname[..link.len()].copy_from_slice(link.as_bytes());
+                    &[]
+                }
+            };
+            header.set_size(data.len() as u64);
+            header.set_cksum();
+            builder.append(&header, data).unwrap();
+        }
+
+        builder.finish().unwrap();
+    }
+
+    fn extract_in(dir: &Path, archive: &Path, strip_components: usize) -> Result<PathBuf> {
+        let dest = dir.join("out");
+        extract(
+            archive.to_str().unwrap(),
+            dest.to_str().unwrap(),
+            strip_components,
+        )?;
+
+        Ok(dest)
+    }
+
+    #[test]
+    fn strips_leading_components() {
+        let dir = temp_dir("strip");
+        let archive = dir.join("pkg.tar");
+        write_tar(
+            &archive,
+            &[
+                ("pkg-1.0/bin/tool", Entry::File(b"tool")),
+                ("pkg-1.0/README", Entry::File(b"readme")),
+                ("pkg-1.0/bin/alias", Entry::HardLink("pkg-1.0/bin/tool")),
+            ],
+        );
+
+        let mut files = list(archive.to_str().unwrap(), 1).unwrap();
+        files.sort();
+        assert_eq!(
+            files,
+            [
+                PathBuf::from("README"),
+                PathBuf::from("bin/alias"),
+                PathBuf::from("bin/tool")
+            ]
+        );
+
+        let dest = extract_in(&dir, &archive, 1).unwrap();
+        assert_eq!(fs::read(dest.join("bin/tool")).unwrap(), b"tool");
+        assert_eq!(fs::read(dest.join("bin/alias")).unwrap(), b"tool");
+        assert_eq!(fs::read(dest.join("README")).unwrap(), b"readme");
+        assert!(!dir.join("out.partial").exists());
+
+        // Entries with nothing left after stripping are skipped
+        fs::remove_dir_all(&dest).unwrap();
+        let dest = extract_in(&dir, &archive, 2).unwrap();
+        assert_eq!(fs::read(dest.join("tool")).unwrap(), b"tool");
+        assert!(!dest.join("README").exists());
+    }
+
+    #[test]
+    fn strips_zip_entries() {
+        let dir = temp_dir("zip");
+        let archive = dir.join("pkg.zip");
+        let mut zip = ZipWriter::new(File::create(&archive).unwrap());
+        zip.start_file("pkg-1.0/src/main.c", SimpleFileOptions::default())
+            .unwrap();
+        zip.write_all(b"int main;").unwrap();
+        zip.finish().unwrap();
+
+        let dest = extract_in(&dir, &archive, 1).unwrap();
+
+        assert_eq!(fs::read(dest.join("src/main.c")).unwrap(), b"int main;");
+    }
+
+    #[test]
+    fn refuses_entries_outside_the_destination() {
+        let dir = temp_dir("traversal");
+
+        for (index, name) in ["pkg/../../evil", "/tmp/evil"].into_iter().enumerate() {
+            let archive = dir.join(format!("evil-{index}.tar"));
+            write_tar(&archive, &[(name, Entry::File(b"evil"))]);
+
+            let err = extract_in(&dir, &archive, 0).unwrap_err();
+            assert!(matches!(err, CrossDevError::BadArchiveEntry(_)), "{err}");
+        }
+        assert!(!dir.join("evil").exists());
+    }
+
+    #[test]
+    fn refuses_to_write_through_symlinks() {
+        let dir = temp_dir("symlink");
+        let outside = dir.join("outside");
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(outside.join("secret"), b"secret").unwrap();
+        let outside = outside.to_str().unwrap();
+
+        let escapes = [
+            // A file below a symlinked directory
+            vec![
+                ("pkg/link", Entry::Symlink(outside)),
+                ("pkg/link/evil", Entry::File(b"evil")),
+            ],
+            // A hard link to a host file reached through a symlink
+            vec![
+                ("pkg/link", Entry::Symlink(outside)),
+                ("pkg/stolen", Entry::HardLink("pkg/link/secret")),
+            ],
+        ];
+        for (index, entries) in escapes.iter().enumerate() {
+            let archive = dir.join(format!("escape-{index}.tar"));
+            write_tar(&archive, entries);
+
+            let err = extract_in(&dir, &archive, 1).unwrap_err();
+            assert!(matches!(err, CrossDevError::BadArchiveEntry(_)), "{err}");
+        }
+        assert!(!dir.join("outside/evil").exists());
+
+        // A file replaces a symlink of the same name instead of following it
+        let archive = dir.join("replace.tar");
+        let secret = format!("{outside}/secret");
+        write_tar(
+            &archive,
+            &[
+                ("pkg/file", Entry::Symlink(&secret)),
+                ("pkg/file", Entry::File(b"mine")),
+            ],
+        );
+        let dest = extract_in(&dir, &archive, 1).unwrap();
+
+        assert_eq!(fs::read(dest.join("file")).unwrap(), b"mine");
+        assert_eq!(fs::read(&secret).unwrap(), b"secret");
+    }
+
+    #[test]
+    fn detects_formats_by_content_then_name() {
+        let dir = temp_dir("detect");
+        let file = |name: &str, head: &[u8]| {
+            let path = dir.join(name);
+            fs::write(&path, head).unwrap();
+            path.display().to_string()
+        };
+        let mut tar = vec![0; 512];
+        tar[USTAR_OFFSET..USTAR_OFFSET + 5].copy_from_slice(b"ustar");
+
+        // Misleading names lose against the content
+        assert_eq!(
+            Format::detect(&file("a.zip", &[0x1f, 0x8b, 8])).unwrap(),
+            Format::TarGz
+        );
+        assert_eq!(
+            Format::detect(&file("a.tar.gz", b"\xfd7zXZ\x00rest")).unwrap(),
+            Format::TarXz
+        );
+        assert_eq!(
+            Format::detect(&file("a.bin", b"BZh91AY")).unwrap(),
+            Format::TarBz2
+        );
+        assert_eq!(
+            Format::detect(&file("download", &tar)).unwrap(),
+            Format::Tar
+        );
+        assert_eq!(
+            Format::detect(&file("a.tgz", b"PK\x03\x04")).unwrap(),
+            Format::Zip
+        );
+
+        // Files not downloaded yet go by their name
+        let missing = dir.join("qemu-9.2.0.TAR.XZ").display().to_string();
+        assert_eq!(Format::detect(&missing).unwrap(), Format::TarXz);
+        assert!(matches!(
+            Format::detect(&file("notes.txt", b"hello")),
+            Err(CrossDevError::UnknownArchiveFormat(_))
+        ));
+    }
+
+    #[test]
+    fn stems_drop_the_archive_extension() {
+        assert_eq!(archive_stem("qemu-9.2.0.tar.xz"), "qemu-9.2.0");
+        assert_eq!(archive_stem("linux-6.6.TGZ"), "linux-6.6");
+        assert_eq!(archive_stem("firmware.bin"), "firmware.bin");
+    }
+}
@@ -1,43 +1,65 @@
-use crate::{config::*, env::*, error::Result, ssh::prepare_ssh_key, traits::Installable};
-use std::{fs, process::Command};
+use crate::{
+    cache::buildroot_dl_dir,
+    config::*,
+    download::*,
+    env::*,
+    error::Result,
+    patch::apply_patches,
+    reporter::info,
+    runner::{copy_file, is_offline, make_dir, RunLogged},
+    ssh::prepare_ssh_key,
+    stamp::*,
+    traits::Installable,
+    utils::*,
+};
+use std::process::Command;
 use xshell::{cmd, Shell};
 
+const BUILD_TYPE_FRAGMENT: &str = "build_type.config";
+const DEFAULT_DEFCONFIG: &str = "qemu_riscv64_virt_riscv_defconfig";
+// Buildroot overrides that take the kernel (headers) from the Linux package's tree
+const LINUX_OVERRIDES: [&str; 2] = ["LINUX_HEADERS_OVERRIDE_SRCDIR", "LINUX_OVERRIDE_SRCDIR"];
+
 #[derive(Debug)]
 pub struct Buildroot {
-    arch: String,
+    build_type: BuildType,
+    flavor: ToolchainFlavor,
     package: Package,
     build_dir: String,
     buildroot_dir: String,
     br_org_custom_dir: String,
     br_custom_dir: String,
-    br_overlay_dir: String,
     bin_name: String,
     bin_path: String,
 }
 
 impl Buildroot {
-    pub fn new(arch: String, package: Package) -> Result<Self> {
+    pub fn new(
+        _arch: String,
+        build_type: BuildType,
+        flavor: ToolchainFlavor,
+        package: Package,
+    ) -> Result<Self> {
         let root_dir = get_dir(EnvType::RootDir)?;
         let download_dir = get_dir(EnvType::DownloadDir)?;
         let build_dir = get_dir(EnvType::BuildDir)?;
-        let buildroot_dir_name = format!("buildroot");
+        let buildroot_dir_name = "buildroot";
         let build_dir = format!("{build_dir}/{buildroot_dir_name}");
-        let buildroot_dir = format!("{download_dir}/{buildroot_dir_name}");
+        let buildroot_dir = source_dir(&package.download, buildroot_dir_name)?;
         let br_org_custom_dir = format!("{root_dir}/custom_buildroot");
         let br_custom_dir = format!("{download_dir}/custom_buildroot");
-        let br_overlay_dir = format!("{br_custom_dir}/board/riscv/overlay");
 
-        let bin_name = format!("rootfs.ext4");
+        let bin_name = "rootfs.ext4".to_string();
         let bin_path = format!("{build_dir}/images/{bin_name}");
 
         Ok(Self {
-            arch,
+            build_type,
+            flavor,
             package,
             build_dir,
             buildroot_dir,
             br_org_custom_dir,
             br_custom_dir,
-            br_overlay_dir,
             bin_name,
             bin_path,
         })
@@ -49,31 +71,32 @@ impl Installable for Buildroot {
         &self.package.name
     }
 
-    fn download(&self) -> Result<()> {
-        let sh = Shell::new()?;
+    fn pack_type(&self) -> PackType {
+        self.package.pack_type
+    }
 
+    fn download(&self) -> Result<()> {
         match &self.package.download {
-            Download::Git(git) => {
-                let repo = git.url.as_str();
-                let branch = git.branch.as_str();
-                let buildroot_dir = self.buildroot_dir.as_str();
-
-                println!("📦 Cloning {repo}...");
-
-                if !sh.path_exists(buildroot_dir) {
-                    cmd!(
-                        sh,
-                        "git clone --depth 1 -b {branch} --single-branch {repo} {buildroot_dir}"
-                    )
-                    .run_echo()?;
-                }
-            }
+            Download::Git(git) => git_clone(self.name(), git, &self.buildroot_dir)?,
+            Download::Path(local) => check_local_source(local)?,
             _ => {}
         }
 
         Ok(())
     }
 
+    fn patch(&self) -> Result<()> {
+        apply_patches(&self.package, &self.buildroot_dir)
+    }
+
+    fn update(&self) -> Result<()> {
+        if let Download::Git(git) = &self.package.download {
+            git_update(self.name(), git, &self.buildroot_dir)?;
+        }
+
+        Ok(())
+    }
+
     fn build(&self) -> Result<()> {
         let mut sh = Shell::new()?;
 
@@ -82,21 +105,75 @@ impl Installable for Buildroot {
         let br_org_custom_dir = self.br_org_custom_dir.as_str();
         let br_custom_dir = self.br_custom_dir.as_str();
         let download_dir = get_dir(EnvType::DownloadDir)?;
-        let br_riscv_config = format!("qemu_riscv64_virt_riscv_defconfig");
-        sh.create_dir(buildroot_build_dir)?;
+        let build = &self.package.build;
+        let mut make_vars = build.make_vars();
+        let targets = &build.targets;
+        let br_riscv_config = build.defconfig.as_deref().unwrap_or(DEFAULT_DEFCONFIG);
+        make_dir(buildroot_build_dir)?;
         sh.set_current_dir(buildroot_dir);
 
+        // Buildroot's own downloads are shared by every profile too, unless
+        // the config picks another directory through `build.env`
+        let dl_dir = match build.env.get("BR2_DL_DIR") {
+            Some(dir) => dir.clone(),
+            None => buildroot_dl_dir()?,
+        };
+        make_dir(&dl_dir)?;
+
+        // Offline, Buildroot may only take sources from its download directory:
+        // what isn't there already is looked up on a primary site in the same
+        // directory and never anywhere else
+        if is_offline() {
+            info(format!(
+                "📴 Buildroot only uses the sources already in {dl_dir}"
+            ));
+            make_vars.extend([
+                format!("BR2_PRIMARY_SITE=file://{dl_dir}"),
+                "BR2_PRIMARY_SITE_ONLY=y".to_string(),
+            ]);
+        }
+        let make_vars = &make_vars;
+        sh.set_var("BR2_DL_DIR", dl_dir);
+
         // In order not to copy intermediate files into the original overlay directory
-        cmd!(sh, "cp -r {br_org_custom_dir} {download_dir}").run_echo()?;
+        cmd!(sh, "cp -r {br_org_custom_dir} {download_dir}").run_logged()?;
 
         // Prepare the ssh key
         prepare_ssh_key(&download_dir)?;
 
         cmd!(
             sh,
-            "make O={buildroot_build_dir} BR2_EXTERNAL={br_custom_dir} {br_riscv_config}"
+            "make O={buildroot_build_dir} BR2_EXTERNAL={br_custom_dir} {make_vars...} {br_riscv_config}"
+        )
+        .run_logged()?;
+
+        // Apply the build type on top of the defconfig
+        let debug = self.build_type == BuildType::Debug;
+        let mut symbols = vec![("BR2_ENABLE_DEBUG", debug), ("BR2_STRIP_strip", !debug)];
+
+        // Buildroot can't use a clang toolchain as an external one, so with
+        // the LLVM flavor it always builds the GCC for its packages itself,
+        // whatever toolchain the defconfig asks for
+        if self.flavor == ToolchainFlavor::Llvm {
+            info("ℹ️  Buildroot builds the root filesystem with its own GCC toolchain");
+            symbols.extend([
+                ("BR2_TOOLCHAIN_BUILDROOT", true),
+                ("BR2_TOOLCHAIN_EXTERNAL", false),
+            ]);
+        }
+
+        let fragment = format!("{buildroot_build_dir}/{BUILD_TYPE_FRAGMENT}");
+        write_kconfig_fragment(&fragment, &symbols)?;
+        cmd!(
+            sh,
+            "support/kconfig/merge_config.sh -m -O {buildroot_build_dir} {buildroot_build_dir}/.config {fragment}"
+        )
+        .run_logged()?;
+        cmd!(
+            sh,
+            "make O={buildroot_build_dir} {make_vars...} olddefconfig"
         )
-        .run_echo()?;
+        .run_logged()?;
 
         sh.set_current_dir(buildroot_build_dir);
 
@@ -108,9 +185,10 @@ impl Installable for Buildroot {
             .stdout;
         let clean_path = String::from_utf8(clean_path)?.trim().to_string();
         sh.set_var("PATH", clean_path);
+        build.apply_env(&mut sh);
 
-        let nproc = cmd!(sh, "nproc").read()?;
-        cmd!(sh, "make -j{nproc}").run_echo()?;
+        let nproc = get_jobs()?;
+        cmd!(sh, "make -j{nproc} {make_vars...} {targets...}").run_logged()?;
 
         Ok(())
     }
@@ -120,13 +198,78 @@ impl Installable for Buildroot {
         let bin_path = self.bin_path.as_str();
         let image_path = format!("{}/{}", get_dir(EnvType::ImageDir)?, self.bin_name);
 
-        println!("📦 Copying {bin_path} to {image_path}...");
+        copy_file(bin_path, &image_path)
+    }
+
+    fn dependencies(&self) -> Vec<PackType> {
+        // The toolchain's C library is built against the headers of that tree
+        let build = &self.package.build;
+        let uses_linux = LINUX_OVERRIDES
+            .iter()
+            .any(|var| build.make_vars.contains_key(*var) || build.env.contains_key(*var));
+
+        if uses_linux {
+            vec![PackType::Linux]
+        } else {
+            Vec::new()
+        }
+    }
+
+    fn clean(&self) -> Result<()> {
+        let mut sh = Shell::new()?;
+        let buildroot_dir = self.buildroot_dir.as_str();
+        let buildroot_build_dir = self.build_dir.as_str();
 
-        fs::copy(bin_path, image_path)?;
+        if sh.path_exists(buildroot_build_dir) && sh.path_exists(buildroot_dir) {
+            sh.set_current_dir(buildroot_dir);
+            cmd!(sh, "make O={buildroot_build_dir} clean").run_logged()?;
+        }
 
         Ok(())
     }
 
+    fn distclean(&self) -> Result<()> {
+        remove_dir(&self.build_dir)?;
+        remove_dir(&self.br_custom_dir)?;
+        remove_sources(&self.package.download, &self.buildroot_dir)
+    }
+
+    fn fingerprint(&self, phase: Phase) -> Result<Option<Fingerprint>> {
+        let download = &self.package.download;
+
+        match phase {
+            Phase::Download => download_fingerprint(&self.package, &self.buildroot_dir),
+            Phase::Build => {
+                let Some(revision) = source_revision(download, &self.buildroot_dir)? else {
+                    return Ok(None);
+                };
+
+                Ok(Some(
+                    Fingerprint::new()
+                        .add("package", format!("{:?}", self.package))
+                        .add("revision", revision)
+                        .add("build_type", self.build_type)
+                        .add("flavor", self.flavor)
+                        .add("overlay", hash_dir(&self.br_org_custom_dir)?)
+                        .add("output", file_signature(&self.bin_path)),
+                ))
+            }
+            Phase::Install => {
+                let image_path = format!("{}/{}", get_dir(EnvType::ImageDir)?, self.bin_name);
+                Ok(Some(install_fingerprint(&self.bin_path, &image_path)))
+            }
+        }
+    }
+
+    fn image_path(&self) -> Result<Option<String>> {
+        let image_dir = get_dir(EnvType::ImageDir)?;
+        Ok(Some(format!("{image_dir}/{}", self.bin_name)))
+    }
+
+    fn source_dir(&self) -> &str {
+        &self.buildroot_dir
+    }
+
     fn build_dir(&self) -> &str {
         &self.build_dir
     }
@@ -135,7 +278,7 @@ impl Installable for Buildroot {
         &self.bin_name
     }
 
-    fn bin_path(&self) -> &str {
-        &self.bin_path
+    fn bin_path(&self) -> Result<&str> {
+        Ok(&self.bin_path)
     }
 }
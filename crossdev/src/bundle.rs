@@ -0,0 +1,334 @@
+use crate::{
+    archive::{is_inside, make_parent, remove_symlink},
+    cache::{buildroot_dl_dir, cache_dir, cached_path},
+    config::*,
+    env::*,
+    error::*,
+    lock::lockfile_location,
+    reporter::info,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File},
+    io::Read,
+    path::{Component, Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tar::{Archive, Builder, EntryType, Header};
+
+const MANIFEST: &str = "bundle.json";
+const DOWNLOADS: &str = "downloads";
+const CACHE: &str = "cache";
+const LOCKFILE: &str = "xdev.lock";
+
+/// What a bundle was made from, stored next to the sources in the archive
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub profile: String,
+    pub created: u64,
+}
+
+/// Packs the profile's downloaded sources, the cached archives its packages use,
+/// Buildroot's downloads and the lockfile into a tar file at `out`
+pub fn export_bundle(config: &Config, out: &str) -> Result<()> {
+    let mut builder = Builder::new(File::create(out)?);
+    // Toolchains and git trees are full of symlinks that must stay symlinks
+    builder.follow_symlinks(false);
+
+    let manifest = serde_json::to_vec_pretty(&Manifest {
+        profile: config.name.clone(),
+        created: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_secs())
+            .unwrap_or_default(),
+    })?;
+    let mut header = Header::new_gnu();
+    header.set_size(manifest.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    builder.append_data(&mut header, MANIFEST, manifest.as_slice())?;
+
+    let download_dir = get_dir(EnvType::DownloadDir)?;
+    if Path::new(&download_dir).is_dir() {
+        info(format!("📦 Adding {download_dir}"));
+        builder.append_dir_all(DOWNLOADS, &download_dir)?;
+    }
+
+    let cache_dir = cache_dir()?;
+    for package in &config.packages {
+        match &package.download {
+            Download::File(file) => {
+                let Some(entry_dir) = Path::new(&cached_path(file)?)
+                    .parent()
+                    .map(Path::to_path_buf)
+                else {
+                    continue;
+                };
+                if entry_dir.is_dir() {
+                    info(format!("📦 Adding {} for {}", file.url, package.name));
+                    builder.append_dir_all(in_cache(&cache_dir, &entry_dir)?, &entry_dir)?;
+                }
+            }
+            Download::Path(local) => info(format!(
+                "📂 Not bundling the local sources of {} at {}",
+                package.name, local.path
+            )),
+            _ => {}
+        }
+    }
+
+    let dl_dir = buildroot_dl_dir()?;
+    if Path::new(&dl_dir).is_dir() {
+        info(format!("📦 Adding {dl_dir}"));
+        builder.append_dir_all(in_cache(&cache_dir, Path::new(&dl_dir))?, &dl_dir)?;
+    }
+
+    if let Some(lockfile) = lockfile_location().filter(|path| path.is_file()) {
+        builder.append_path_with_name(lockfile, LOCKFILE)?;
+    }
+
+    builder.into_inner()?.sync_all()?;
+
+    Ok(())
+}
+
+/// Unpacks a bundle made by `export_bundle` into this profile's download
+/// directory, the download cache and the lockfile
+pub fn import_bundle(config: &Config, bundle: &str) -> Result<Option<Manifest>> {
+    let download_dir = PathBuf::from(get_dir(EnvType::DownloadDir)?);
+    let cache_dir = PathBuf::from(cache_dir()?);
+    fs::create_dir_all(&cache_dir)?;
+    // Symlinks unpacked earlier must not lead later entries out of these
+    let download_root = download_dir.canonicalize()?;
+    let cache_root = cache_dir.canonicalize()?;
+    // Where a `downloads/` or `cache/` path of the bundle goes, below which root
+    let place = |path: &Path| {
+        if let Ok(rest) = path.strip_prefix(DOWNLOADS) {
+            Some((download_root.as_path(), download_dir.join(rest)))
+        } else if let Ok(rest) = path.strip_prefix(CACHE) {
+            Some((cache_root.as_path(), cache_dir.join(rest)))
+        } else {
+            None
+        }
+    };
+    let mut manifest = None;
+    let mut archive = Archive::new(File::open(bundle)?);
+    archive.set_preserve_mtime(true);
+    archive.set_preserve_permissions(true);
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.to_path_buf();
+        if path
+            .components()
+            .any(|component| !matches!(component, Component::Normal(_)))
+        {
+            return Err(CrossDevError::BadBundleEntry(path.display().to_string()));
+        }
+
+        let bad_entry = || CrossDevError::BadBundleEntry(path.display().to_string());
+
+        if path == Path::new(MANIFEST) {
+            let mut json = String::new();
+            entry.read_to_string(&mut json)?;
+            let read: Manifest = serde_json::from_str(&json)?;
+            if read.profile != config.name {
+                info(format!(
+                    "⚠️  Bundle was exported from profile {}, importing it into {}",
+                    read.profile, config.name
+                ));
+            }
+            manifest = Some(read);
+            continue;
+        }
+
+        if path == Path::new(LOCKFILE) {
+            if let Some(lockfile) = lockfile_location() {
+                info(format!("🔒 Restoring {}", lockfile.display()));
+                if let Some(parent) = lockfile.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                remove_symlink(&lockfile)?;
+                entry.unpack(&lockfile)?;
+            }
+            continue;
+        }
+
+        // Both directories exist already
+        if path == Path::new(DOWNLOADS) || path == Path::new(CACHE) {
+            continue;
+        }
+        let (root, dest) = place(&path).ok_or_else(bad_entry)?;
+        if !make_parent(root, &dest)? {
+            return Err(bad_entry());
+        }
+
+        match entry.header().entry_type() {
+            // Linked the same way as the file it points at, never to one outside
+            EntryType::Link => {
+                let link = entry.link_name()?.ok_or_else(bad_entry)?.into_owned();
+                let (_, original) = place(&link).ok_or_else(bad_entry)?;
+                if !is_inside(root, &original) {
+                    return Err(bad_entry());
+                }
+                remove_symlink(&dest)?;
+                fs::hard_link(original, &dest)?;
+            }
+            EntryType::Directory => {
+                if dest.symlink_metadata().is_ok() && !is_inside(root, &dest) {
+                    return Err(bad_entry());
+                }
+                entry.unpack(&dest)?;
+            }
+            _ => {
+                remove_symlink(&dest)?;
+                entry.unpack(&dest)?;
+            }
+        }
+    }
+
+    Ok(manifest)
+}
+
+// Name of `path`, somewhere below `cache_dir`, inside the bundle's `cache/`
+fn in_cache(cache_dir: &str, path: &Path) -> Result<PathBuf> {
+    let relative = path
+        .strip_prefix(cache_dir)
+        .map_err(|_| CrossDevError::OutsideCacheDir {
+            path: path.display().to_string(),
+            cache_dir: cache_dir.to_string(),
+        })?;
+
+    Ok(Path::new(CACHE).join(relative))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        archive::tests::{write_tar, Entry},
+        cache::test_cache_dir,
+    };
+    use std::{os::unix::fs::symlink, sync::Mutex};
+
+    // Every bundle covers the whole download directory, so the tests take turns
+    static BUNDLES: Mutex<()> = Mutex::new(());
+
+    fn config(packages: &str) -> Config {
+        toml::from_str(&format!(
+            "name = \"bundle\"\narch = \"riscv64\"\nbuild_type = \"debug\"\npackages = [{packages}]\n"
+        ))
+        .unwrap()
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("crossdev-bundle-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        dir
+    }
+
+    // A directory of the test's own in the download directory
+    fn download_dir(name: &str) -> PathBuf {
+        let dir = Path::new(test_env_vars().download_dir()).join(name);
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        dir
+    }
+
+    #[test]
+    fn round_trips_sources_and_cached_archives() {
+        let _bundles = BUNDLES.lock().unwrap();
+        test_cache_dir();
+        let dir = temp_dir("round-trip");
+
+        let sources = download_dir("round-trip");
+        fs::write(sources.join("main.c"), "int main;").unwrap();
+        symlink("main.c", sources.join("link.c")).unwrap();
+
+        let config = config(
+            "{ name = \"hello\", pack_type = \"custom\", version = \"1\", download_type = \"file\", download = { url = \"https://example.com/round-trip.tar.gz\" } }",
+        );
+        let Download::File(file) = &config.packages[0].download else {
+            unreachable!();
+        };
+        let archive = PathBuf::from(cached_path(file).unwrap());
+        fs::create_dir_all(archive.parent().unwrap()).unwrap();
+        fs::write(&archive, "archive").unwrap();
+
+        let bundle = dir.join("bundle.tar").display().to_string();
+        export_bundle(&config, &bundle).unwrap();
+        fs::remove_dir_all(&sources).unwrap();
+        fs::remove_dir_all(archive.parent().unwrap()).unwrap();
+
+        let manifest = import_bundle(&config, &bundle).unwrap().unwrap();
+        assert_eq!(manifest.profile, "bundle");
+        assert_eq!(
+            fs::read_to_string(sources.join("main.c")).unwrap(),
+            "int main;"
+        );
+        assert_eq!(
+            fs::read_link(sources.join("link.c")).unwrap(),
+            Path::new("main.c")
+        );
+        assert_eq!(fs::read_to_string(&archive).unwrap(), "archive");
+    }
+
+    #[test]
+    fn refuses_entries_escaping_the_download_directory() {
+        let _bundles = BUNDLES.lock().unwrap();
+        test_cache_dir();
+        let dir = temp_dir("escape");
+        let outside = dir.join("outside");
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(outside.join("secret"), "secret").unwrap();
+        let outside = outside.to_str().unwrap();
+        download_dir("escape");
+
+        let escapes = [
+            // A path climbing out with `..`
+            vec![("downloads/escape/../../../evil", Entry::File(b"evil"))],
+            // A file below a symlinked directory
+            vec![
+                ("downloads/escape/link", Entry::Symlink(outside)),
+                ("downloads/escape/link/evil", Entry::File(b"evil")),
+            ],
+            // A hard link to a host file reached through a symlink
+            vec![
+                ("downloads/escape/link", Entry::Symlink(outside)),
+                (
+                    "downloads/escape/stolen",
+                    Entry::HardLink("downloads/escape/link/secret"),
+                ),
+            ],
+            // A hard link to a host file by its absolute path
+            vec![("downloads/escape/stolen", Entry::HardLink("/etc/passwd"))],
+        ];
+        for (index, entries) in escapes.iter().enumerate() {
+            let bundle = dir.join(format!("escape-{index}.tar"));
+            write_tar(&bundle, entries);
+
+            let err = import_bundle(&config(""), bundle.to_str().unwrap()).unwrap_err();
+            assert!(matches!(err, CrossDevError::BadBundleEntry(_)), "{err}");
+        }
+        assert!(!dir.join("outside/evil").exists());
+        assert!(!Path::new(test_env_vars().download_dir())
+            .join("escape/stolen")
+            .exists());
+    }
+
+    #[test]
+    fn only_bundles_paths_inside_the_cache() {
+        assert_eq!(
+            in_cache("/cache", Path::new("/cache/files/key")).unwrap(),
+            Path::new("cache/files/key")
+        );
+        assert!(matches!(
+            in_cache("/cache", Path::new("/elsewhere/files/key")),
+            Err(CrossDevError::OutsideCacheDir { .. })
+        ));
+    }
+}
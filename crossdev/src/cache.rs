@@ -0,0 +1,313 @@
+use crate::{
+    config::FileDownload,
+    error::*,
+    stamp::Fingerprint,
+    utils::{expand_home, get_work_dir, remove_dir},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const DEFAULT_CACHE_DIR: &str = "cache";
+const CACHE_DIR_VAR: &str = "CROSSDEV_CACHE_DIR";
+const FILES_DIR: &str = "files";
+const BUILDROOT_DL_DIR: &str = "buildroot-dl";
+const ENTRY_FILE: &str = "entry.json";
+/// Held next to a cached file while it is being downloaded
+pub const LOCK_SUFFIX: &str = ".lock";
+
+static CACHE_DIR: OnceLock<String> = OnceLock::new();
+
+/// A downloaded file shared by every profile, stored under a key derived from
+/// its URL and checksums
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CacheEntry {
+    pub key: String,
+    pub url: String,
+    pub sha256: String,
+    pub size: u64,
+    // Unix time of the last setup that used the file
+    pub last_used: u64,
+    #[serde(skip_deserializing)]
+    pub path: String,
+}
+
+/// Overrides the cache directory, which otherwise comes from
+/// `CROSSDEV_CACHE_DIR` or defaults to `cache/` next to the profiles
+pub fn set_cache_dir(dir: &str) -> Result<()> {
+    CACHE_DIR
+        .set(expand_home(dir)?)
+        .map_err(|_| CrossDevError::CacheDirAlreadySet)?;
+    Ok(())
+}
+
+/// The cache directory of unit tests, shared by all of them
+#[cfg(test)]
+pub(crate) fn test_cache_dir() -> &'static str {
+    CACHE_DIR.get_or_init(|| {
+        let dir = std::env::temp_dir().join(format!("crossdev-cache-{}", std::process::id()));
+        dir.display().to_string()
+    })
+}
+
+pub fn cache_dir() -> Result<String> {
+    if let Some(dir) = CACHE_DIR.get() {
+        return Ok(dir.clone());
+    }
+
+    match env::var(CACHE_DIR_VAR) {
+        Ok(dir) if !dir.is_empty() => expand_home(&dir),
+        _ => Ok(format!("{}/{DEFAULT_CACHE_DIR}", get_work_dir()?)),
+    }
+}
+
+/// Where Buildroot keeps the package sources it downloads (`BR2_DL_DIR`)
+pub fn buildroot_dl_dir() -> Result<String> {
+    Ok(format!("{}/{BUILDROOT_DL_DIR}", cache_dir()?))
+}
+
+/// Path `file` is downloaded to, whether or not it is cached yet
+pub fn cached_path(file: &FileDownload) -> Result<String> {
+    let filename = file.url.split('/').next_back().unwrap_or_default();
+
+    Ok(format!("{}/{filename}", entry_dir(&cache_key(file))?))
+}
+
+/// Remembers that `path`, the cached copy of `file`, was just used
+pub fn record_entry(file: &FileDownload, path: &str, sha256: &str) -> Result<()> {
+    let key = cache_key(file);
+    let entry = CacheEntry {
+        url: file.url.clone(),
+        sha256: sha256.to_string(),
+        size: fs::metadata(path)?.len(),
+        last_used: now(),
+        path: path.to_string(),
+        key: key.clone(),
+    };
+
+    let entry_path = format!("{}/{ENTRY_FILE}", entry_dir(&key)?);
+    fs::write(entry_path, serde_json::to_string_pretty(&entry)?)?;
+
+    Ok(())
+}
+
+/// Marks the cached copy of `file`, if there is one, as just used
+pub fn touch_entry(file: &FileDownload) -> Result<()> {
+    let dir = PathBuf::from(entry_dir(&cache_key(file))?);
+    let Some(mut entry) = read_entry(&dir) else {
+        return Ok(());
+    };
+
+    entry.last_used = now();
+    fs::write(dir.join(ENTRY_FILE), serde_json::to_string_pretty(&entry)?)?;
+
+    Ok(())
+}
+
+/// Drops the cached copy of `file`, so the next fetch downloads it again
+pub fn evict_entry(file: &FileDownload) -> Result<()> {
+    remove_dir(&entry_dir(&cache_key(file))?)
+}
+
+/// Every cached file, least recently used first
+pub fn list_entries() -> Result<Vec<CacheEntry>> {
+    let mut entries = Vec::new();
+    for dir in entry_dirs()? {
+        let Some(mut entry) = read_entry(&dir) else {
+            continue;
+        };
+
+        let filename = entry.url.split('/').next_back().unwrap_or_default();
+        entry.path = dir.join(filename).display().to_string();
+        entries.push(entry);
+    }
+    entries.sort_by_key(|entry| entry.last_used);
+
+    Ok(entries)
+}
+
+/// Removes the files not used for `older_than` seconds, or everything
+/// (including Buildroot's downloads) without it, and returns what was removed
+pub fn prune(older_than: Option<u64>) -> Result<Vec<CacheEntry>> {
+    let expired =
+        |last_used: u64| older_than.is_none_or(|age| now().saturating_sub(last_used) > age);
+    let mut removed = Vec::new();
+
+    for entry in list_entries()? {
+        if expired(entry.last_used) {
+            remove_dir(&entry_dir(&entry.key)?)?;
+            removed.push(entry);
+        }
+    }
+
+    // Directories without an entry are left by interrupted downloads; the
+    // partial file is kept for resuming until it's as old as unused entries
+    for dir in entry_dirs()? {
+        if read_entry(&dir).is_some() || is_downloading(&dir)? {
+            continue;
+        }
+
+        let orphan = orphaned_entry(&dir)?;
+        if expired(orphan.last_used) {
+            remove_dir(&orphan.path)?;
+            removed.push(orphan);
+        }
+    }
+
+    if older_than.is_none() {
+        remove_dir(&buildroot_dl_dir()?)?;
+    }
+
+    Ok(removed)
+}
+
+// The same URL with different checksums is a different file
+fn cache_key(file: &FileDownload) -> String {
+    Fingerprint::new()
+        .add("url", &file.url)
+        .add("sha256", file.sha256.as_deref().unwrap_or_default())
+        .add("sha512", file.sha512.as_deref().unwrap_or_default())
+        .digest()
+}
+
+fn entry_dirs() -> Result<Vec<PathBuf>> {
+    let files_dir = format!("{}/{FILES_DIR}", cache_dir()?);
+    if !Path::new(&files_dir).is_dir() {
+        return Ok(Vec::new());
+    }
+
+    fs::read_dir(&files_dir)?
+        .map(|dir| Ok(dir?.path()))
+        .collect()
+}
+
+fn read_entry(dir: &Path) -> Option<CacheEntry> {
+    let json = fs::read_to_string(dir.join(ENTRY_FILE)).ok()?;
+
+    serde_json::from_str(&json).ok()
+}
+
+// An entry directory without an entry stands for itself: it has no URL or
+// checksum, and was last used when a file in it was last written
+fn orphaned_entry(dir: &Path) -> Result<CacheEntry> {
+    let (mut size, mut last_used) = (0, 0);
+    for file in fs::read_dir(dir)? {
+        let metadata = file?.metadata()?;
+        let modified = metadata.modified()?.duration_since(UNIX_EPOCH);
+        size += metadata.len();
+        last_used = last_used.max(modified.map(|time| time.as_secs()).unwrap_or_default());
+    }
+
+    Ok(CacheEntry {
+        key: dir
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default(),
+        url: String::new(),
+        sha256: String::new(),
+        size,
+        last_used,
+        path: dir.display().to_string(),
+    })
+}
+
+// Whether another run holds the lock of a download into `dir`
+fn is_downloading(dir: &Path) -> Result<bool> {
+    for file in fs::read_dir(dir)? {
+        let path = file?.path();
+        if !path.to_string_lossy().ends_with(LOCK_SUFFIX) {
+            continue;
+        }
+
+        if let Err(fs::TryLockError::WouldBlock) = fs::File::open(&path)?.try_lock() {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+fn entry_dir(key: &str) -> Result<String> {
+    Ok(format!("{}/{FILES_DIR}/{key}", cache_dir()?))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const DAY: u64 = 24 * 60 * 60;
+
+    // The cache directory is global, so every test uses keys of its own
+    fn entry_path(key: &str) -> PathBuf {
+        test_cache_dir();
+        let dir = PathBuf::from(entry_dir(key).unwrap());
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        dir
+    }
+
+    // Writes `name` in `dir` as if it was last written `age` seconds ago
+    fn write_aged(dir: &Path, name: &str, age: u64) -> fs::File {
+        let file = fs::File::create(dir.join(name)).unwrap();
+        file.set_modified(SystemTime::now() - Duration::from_secs(age))
+            .unwrap();
+
+        file
+    }
+
+    #[test]
+    fn prunes_orphaned_partial_downloads() {
+        let stale = entry_path("stale-orphan");
+        write_aged(&stale, "a.tar.gz.partial", 10 * DAY);
+        write_aged(&stale, "a.tar.gz.partial.validator", 10 * DAY);
+
+        let recent = entry_path("recent-orphan");
+        write_aged(&recent, "b.tar.gz.partial", 0);
+
+        // A download still running in another process keeps its lock
+        let busy = entry_path("busy-orphan");
+        write_aged(&busy, "c.tar.gz.partial", 10 * DAY);
+        let lock = write_aged(&busy, &format!("c.tar.gz{LOCK_SUFFIX}"), 10 * DAY);
+        lock.lock().unwrap();
+
+        let removed = prune(Some(DAY)).unwrap();
+        let removed: Vec<_> = removed.iter().map(|entry| entry.key.as_str()).collect();
+
+        assert!(removed.contains(&"stale-orphan"));
+        assert!(!stale.exists());
+        assert!(!removed.contains(&"recent-orphan"));
+        assert!(recent.exists());
+        assert!(!removed.contains(&"busy-orphan"));
+        assert!(busy.exists());
+    }
+
+    #[test]
+    fn touching_an_entry_marks_it_used() {
+        let file: FileDownload =
+            toml::from_str("url = \"https://example.com/touch.tar.gz\"").unwrap();
+        let dir = entry_path(&cache_key(&file));
+        let path = dir.join("touch.tar.gz").display().to_string();
+        fs::write(&path, "archive").unwrap();
+        record_entry(&file, &path, "sha").unwrap();
+
+        let mut entry = read_entry(&dir).unwrap();
+        entry.last_used = 0;
+        fs::write(dir.join(ENTRY_FILE), serde_json::to_string(&entry).unwrap()).unwrap();
+
+        touch_entry(&file).unwrap();
+        assert!(now() - read_entry(&dir).unwrap().last_used < DAY);
+    }
+}
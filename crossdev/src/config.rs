@@ -1,60 +1,271 @@
-use color_eyre::eyre::Result;
+use crate::{
+    interpolate::interpolate_packages, utils::get_root_dir, validate::ensure_valid_config,
+};
+use color_eyre::eyre::{bail, eyre, Result};
 use core::fmt;
-use serde::Deserialize;
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize};
+use std::collections::BTreeMap;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use xshell::Shell;
+
+const BASE_CONFIG_DIR: &str = "configs";
 
 #[derive(Debug, Deserialize)]
 pub struct Config {
+    #[serde(default)]
+    pub extends: Option<String>,
     pub name: String,
     pub arch: String,
-    pub build_type: String,
+    pub build_type: BuildType,
+    #[serde(default)]
+    pub sanitizers: Vec<Sanitizer>,
+    // Compilers the toolchain provides to the packages built with it
+    #[serde(default)]
+    pub toolchain_flavor: ToolchainFlavor,
+    // URL prefix rewrites applied to every download, e.g. to an internal mirror
+    #[serde(default)]
+    pub mirrors: BTreeMap<String, String>,
     pub packages: Vec<Package>,
 }
 
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BuildType {
+    #[default]
+    Debug,
+    Release,
+}
+
+impl fmt::Display for BuildType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BuildType::Debug => write!(f, "debug"),
+            BuildType::Release => write!(f, "release"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ToolchainFlavor {
+    #[default]
+    Gnu,
+    Llvm,
+}
+
+impl fmt::Display for ToolchainFlavor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ToolchainFlavor::Gnu => write!(f, "gnu"),
+            ToolchainFlavor::Llvm => write!(f, "llvm"),
+        }
+    }
+}
+
+// Kernel sanitizers, only enabled for debug builds
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Sanitizer {
+    Kasan,
+    Kcsan,
+    Ubsan,
+}
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct Package {
     pub name: String,
     pub pack_type: PackType,
     pub version: String,
+    // `download_type` picks how the `download` table is read
     #[serde(flatten)]
-    pub download_type: DownloadType,
     pub download: Download,
+    #[serde(default)]
+    pub build: BuildOptions,
+    // Names of other packages that must be set up before this one
+    #[serde(default)]
+    pub depends_on: Vec<String>,
+    // Shell steps of a `custom` package
+    #[serde(default)]
+    pub script: ScriptSteps,
+    // Globs of patch files, relative to the repository root, applied in order
+    // after every fresh download
+    #[serde(default)]
+    pub patches: Vec<String>,
 }
 
-#[derive(Clone, Debug, Deserialize)]
-#[serde(tag = "download_type", rename_all = "lowercase")]
-pub enum DownloadType {
-    Git,
-    File,
-    Apt,
+/// Shell steps of a `custom` package, each run with `sh -c` in its build directory
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScriptSteps {
+    #[serde(default)]
+    pub build: Vec<String>,
+    #[serde(default)]
+    pub install: Vec<String>,
+    // Files the package produces, relative to its build directory, that get
+    // copied to the image directory
+    #[serde(default)]
+    pub artifacts: Vec<String>,
 }
 
-#[derive(Clone, Debug, Deserialize)]
-#[serde(untagged)]
+/// Extra options passed through to a package's build commands
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BuildOptions {
+    // Replaces the package's default defconfig target (Linux, Buildroot)
+    #[serde(default)]
+    pub defconfig: Option<String>,
+    // `KEY=VALUE` variables added to every make invocation
+    #[serde(default)]
+    pub make_vars: BTreeMap<String, String>,
+    // Extra flags for `configure` (QEMU)
+    #[serde(default)]
+    pub configure_flags: Vec<String>,
+    // Environment variables set for every build command
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    // Make targets built instead of the default one
+    #[serde(default)]
+    pub targets: Vec<String>,
+}
+
+impl BuildOptions {
+    pub fn make_vars(&self) -> Vec<String> {
+        self.make_vars
+            .iter()
+            .map(|(key, value)| format!("{key}={value}"))
+            .collect()
+    }
+
+    pub fn apply_env(&self, sh: &mut Shell) {
+        for (key, value) in &self.env {
+            sh.set_var(key, value);
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
 pub enum Download {
     Git(GitDownload),
     File(FileDownload),
     Apt(AptDownload),
+    Path(PathDownload),
+    System(SystemDownload),
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum DownloadType {
+    Git,
+    File,
+    Apt,
+    Path,
+    System,
+}
+
+// `download` is read as the variant named by the package's `download_type`,
+// and may be left out when that variant has no required keys
+impl<'de> Deserialize<'de> for Download {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct Tagged {
+            download_type: DownloadType,
+            #[serde(default)]
+            download: Option<toml::Value>,
+        }
+
+        let Tagged {
+            download_type,
+            download,
+        } = Tagged::deserialize(deserializer)?;
+        let download = download.unwrap_or_else(|| toml::Value::Table(toml::Table::new()));
+        match download_type {
+            DownloadType::Git => download.try_into().map(Download::Git),
+            DownloadType::File => download.try_into().map(Download::File),
+            DownloadType::Apt => download.try_into().map(Download::Apt),
+            DownloadType::Path => download.try_into().map(Download::Path),
+            DownloadType::System => download.try_into().map(Download::System),
+        }
+        .map_err(D::Error::custom)
+    }
 }
 
 #[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct GitDownload {
     pub url: String,
-    pub branch: String,
+    // What to check out: a branch to follow, or a tag or commit to pin;
+    // the remote's default branch without any of them
+    #[serde(default)]
+    pub branch: Option<String>,
+    #[serde(default)]
+    pub tag: Option<String>,
+    #[serde(default)]
+    pub rev: Option<String>,
+    // Commits of history to fetch, 0 for all of it
+    #[serde(default = "default_depth")]
+    pub depth: u32,
+    #[serde(default)]
+    pub submodules: bool,
+    // Fallback URLs, tried in order when `url` fails
+    #[serde(default)]
+    pub mirrors: Vec<String>,
+}
+
+fn default_depth() -> u32 {
+    1
 }
 
 #[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct FileDownload {
     pub url: String,
+    // Fallback URLs, tried in order when `url` fails
+    #[serde(default)]
+    pub mirrors: Vec<String>,
+    #[serde(default)]
+    pub sha256: Option<String>,
+    #[serde(default)]
+    pub sha512: Option<String>,
+    // Directory under the download directory the archive is extracted into,
+    // instead of one named after the package
+    #[serde(default)]
+    pub extract_dir: Option<String>,
+    // Leading directories dropped from every path in the archive, 1 by
+    // default for the single top-level directory most tarballs have
+    #[serde(default)]
+    pub strip_components: Option<usize>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct AptDownload {
     pub package_name: String,
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize)]
+// An existing source tree on the host, built in place (out of tree)
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PathDownload {
+    pub path: String,
+}
+
+// A tool already installed on the host, used instead of building one
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SystemDownload {
+    // Executable to look for, the package type's usual one by default
+    #[serde(default)]
+    pub binary: Option<String>,
+    // Installation prefix whose `bin` directory is searched instead of PATH
+    #[serde(default)]
+    pub prefix: Option<String>,
+    // Oldest acceptable version, compared with what `binary --version` prints
+    #[serde(default)]
+    pub min_version: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
 #[serde(rename_all = "lowercase")]
 pub enum PackType {
     Toolchain,
@@ -62,6 +273,8 @@ pub enum PackType {
     Opensbi,
     Linux,
     Buildroot,
+    // Defined entirely by the config's `script` steps
+    Custom,
 }
 
 impl fmt::Display for PackType {
@@ -70,12 +283,292 @@ impl fmt::Display for PackType {
     }
 }
 
+/// One file of a config inheritance chain
+#[derive(Debug, Clone)]
+pub struct ConfigSource {
+    pub path: PathBuf,
+    pub content: String,
+}
+
+/// Loads a config file and everything it `extends`, refusing it if the
+/// validator finds any problem
 pub fn read_config_from_file<P>(path: P) -> Result<Config>
 where
     P: AsRef<Path> + std::fmt::Debug,
 {
-    let content = fs::read_to_string(path)?;
-    let config: Config = toml::from_str(&content)?;
+    let sources = read_config_chain(&path)?;
+    ensure_valid_config(path.as_ref(), &sources)?;
+
+    let mut table = merge_config_sources(&sources)?;
+    if let Some(err) = interpolate_packages(&mut table).into_iter().next() {
+        return Err(err.into());
+    }
+    let config: Config = toml::Value::Table(table).try_into()?;
 
     Ok(config)
 }
+
+/// Loads a config file and everything it `extends`, merged into a single table
+/// with the `${...}` placeholders in its packages expanded
+pub fn resolve_config_file<P>(path: P) -> Result<toml::Table>
+where
+    P: AsRef<Path>,
+{
+    let mut config = merge_config_sources(&read_config_chain(path)?)?;
+
+    if let Some(err) = interpolate_packages(&mut config).into_iter().next() {
+        return Err(err.into());
+    }
+
+    Ok(config)
+}
+
+/// Follows the `extends` keys starting at `path`, returning the files base-first
+pub fn read_config_chain<P>(path: P) -> Result<Vec<ConfigSource>>
+where
+    P: AsRef<Path>,
+{
+    let mut chain: Vec<ConfigSource> = Vec::new();
+    let mut next = Some(path.as_ref().to_path_buf());
+
+    while let Some(path) = next.take() {
+        let content = fs::read_to_string(&path)
+            .map_err(|err| eyre!("Failed to read config {}: {err}", path.display()))?;
+
+        // A file that doesn't parse ends the chain; the error surfaces when merging
+        let extends = toml::from_str::<toml::Table>(&content)
+            .ok()
+            .and_then(|table| table.get("extends")?.as_str().map(str::to_string));
+
+        if let Some(extends) = extends {
+            let base = resolve_extends(&path, &extends)?;
+
+            if base == path || chain.iter().any(|source| source.path == base) {
+                bail!(
+                    "Config inheritance cycle: {} extends {}",
+                    path.display(),
+                    base.display()
+                );
+            }
+            next = Some(base);
+        }
+
+        chain.push(ConfigSource { path, content });
+    }
+
+    chain.reverse();
+
+    Ok(chain)
+}
+
+/// Merges an inheritance chain (base-first) into a single table
+pub fn merge_config_sources(sources: &[ConfigSource]) -> Result<toml::Table> {
+    let mut merged = toml::Table::new();
+
+    for source in sources {
+        let table: toml::Table = toml::from_str(&source.content)
+            .map_err(|err| eyre!("Failed to parse {}: {err}", source.path.display()))?;
+        merge_tables(&mut merged, table);
+    }
+
+    Ok(merged)
+}
+
+// `extends` is relative to the extending file, falling back to the repo's configs dir
+fn resolve_extends(path: &Path, extends: &str) -> Result<PathBuf> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let candidate = dir.join(extends);
+
+    if candidate.exists() {
+        return Ok(candidate);
+    }
+
+    if Path::new(extends).is_relative() {
+        if let Ok(root_dir) = get_root_dir() {
+            let candidate = Path::new(&root_dir).join(BASE_CONFIG_DIR).join(extends);
+            if candidate.exists() {
+                return Ok(candidate);
+            }
+        }
+    }
+
+    bail!(
+        "Base config \"{extends}\" extended from {} not found",
+        path.display()
+    )
+}
+
+fn merge_tables(base: &mut toml::Table, overlay: toml::Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Array(base_packages)), toml::Value::Array(packages))
+                if key == "packages" =>
+            {
+                merge_packages(base_packages, packages)
+            }
+            (Some(toml::Value::Table(base_table)), toml::Value::Table(table)) => {
+                merge_tables(base_table, table)
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+// Packages are matched by `name`; unknown names are appended
+fn merge_packages(base: &mut Vec<toml::Value>, overlay: Vec<toml::Value>) {
+    for package in overlay {
+        let name = package.get("name").and_then(|name| name.as_str());
+        let existing = base
+            .iter_mut()
+            .find(|base| name.is_some() && base.get("name").and_then(|n| n.as_str()) == name);
+
+        match (existing, package) {
+            (Some(toml::Value::Table(existing)), toml::Value::Table(package)) => {
+                // Switching the download type invalidates every field of the old download
+                let download_type = package.get("download_type");
+                if download_type.is_some() && download_type != existing.get("download_type") {
+                    existing.remove("download");
+                }
+                merge_tables(existing, package);
+            }
+            (_, package) => base.push(package),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn merge(files: &[&str]) -> toml::Table {
+        let sources: Vec<_> = files
+            .iter()
+            .enumerate()
+            .map(|(index, content)| ConfigSource {
+                path: PathBuf::from(format!("{index}.toml")),
+                content: content.to_string(),
+            })
+            .collect();
+
+        merge_config_sources(&sources).unwrap()
+    }
+
+    fn package<'a>(table: &'a toml::Table, name: &str) -> &'a toml::Value {
+        table["packages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|package| package["name"].as_str() == Some(name))
+            .unwrap()
+    }
+
+    #[test]
+    fn overrides_scalars_and_merges_tables() {
+        let merged = merge(&[
+            "name = \"base\"\nbuild_type = \"debug\"\n[mirrors]\n\"https://a/\" = \"https://m/a/\"\n",
+            "name = \"child\"\n[mirrors]\n\"https://b/\" = \"https://m/b/\"\n",
+        ]);
+
+        assert_eq!(merged["name"].as_str(), Some("child"));
+        assert_eq!(merged["build_type"].as_str(), Some("debug"));
+        assert_eq!(merged["mirrors"].as_table().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn merges_packages_by_name_and_appends_new_ones() {
+        let merged = merge(&[
+            r#"
+[[packages]]
+name = "linux"
+version = "1"
+build = { targets = ["Image"], make_vars = { W = "1" } }
+"#,
+            r#"
+[[packages]]
+name = "hello"
+version = "2"
+
+[[packages]]
+name = "linux"
+version = "2"
+build = { make_vars = { LLVM = "1" } }
+"#,
+        ]);
+
+        let packages = merged["packages"].as_array().unwrap();
+        assert_eq!(packages.len(), 2);
+        assert_eq!(packages[0]["name"].as_str(), Some("linux"));
+
+        let linux = package(&merged, "linux");
+        assert_eq!(linux["version"].as_str(), Some("2"));
+        assert_eq!(linux["build"]["targets"].as_array().unwrap().len(), 1);
+        assert_eq!(linux["build"]["make_vars"].as_table().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn a_new_download_type_drops_the_old_download() {
+        let merged = merge(&[
+            r#"
+[[packages]]
+name = "linux"
+download_type = "git"
+download = { url = "https://example.com/linux.git", branch = "master" }
+"#,
+            r#"
+[[packages]]
+name = "linux"
+download_type = "path"
+download = { path = "/src/linux" }
+"#,
+        ]);
+
+        let download = package(&merged, "linux")["download"].as_table().unwrap();
+        assert_eq!(download.keys().collect::<Vec<_>>(), ["path"]);
+    }
+
+    #[test]
+    fn the_same_download_type_merges_the_download() {
+        let merged = merge(&[
+            "[[packages]]\nname = \"linux\"\ndownload_type = \"git\"\ndownload = { url = \"u\", branch = \"master\" }\n",
+            "[[packages]]\nname = \"linux\"\ndownload = { branch = \"next\" }\n",
+        ]);
+
+        let download = &package(&merged, "linux")["download"];
+        assert_eq!(download["url"].as_str(), Some("u"));
+        assert_eq!(download["branch"].as_str(), Some("next"));
+    }
+
+    fn parse(package: &str) -> Package {
+        toml::from_str(package).unwrap()
+    }
+
+    #[test]
+    fn download_type_picks_the_download_variant() {
+        let package = "name = \"linux\"\npack_type = \"linux\"\nversion = \"6\"\n";
+
+        let git = parse(&format!(
+            "{package}download_type = \"git\"\ndownload = {{ url = \"u\" }}\n"
+        ));
+        assert!(matches!(git.download, Download::Git(ref git) if git.url == "u" && git.depth == 1));
+
+        let file = parse(&format!(
+            "{package}download_type = \"file\"\ndownload = {{ url = \"u\" }}\n"
+        ));
+        assert!(matches!(file.download, Download::File(_)));
+
+        let system = parse(&format!("{package}download_type = \"system\"\n"));
+        assert!(matches!(system.download, Download::System(_)));
+
+        let wrong = format!(
+            "{package}download_type = \"file\"\ndownload = {{ url = \"u\", branch = \"b\" }}\n"
+        );
+        assert!(toml::from_str::<Package>(&wrong).is_err());
+
+        let misspelled = format!(
+            "{package}download_type = \"git\"\ndownload = {{ url = \"u\", comit = \"c\" }}\n"
+        );
+        assert!(toml::from_str::<Package>(&misspelled).is_err());
+    }
+}
@@ -1,17 +1,28 @@
 use crate::{
     buildroot::Buildroot,
+    bundle::{export_bundle, import_bundle, Manifest},
     config::*,
+    custom::Custom,
     env::*,
     error::*,
     linux::Linux,
+    mirror::set_mirrors,
     opensbi::Opensbi,
     qemu::Qemu,
+    reporter::{info, reporting_to, EmojiReporter, Reporter},
+    runner::{is_dry_run, make_dir},
     ssh::SSH_PORT,
-    toolchain::*,
+    stamp::remove_stamps,
+    status::*,
+    toolchain::Toolchain,
     traits::*,
-    utils::{create_dir, get_root_dir, get_work_dir},
+    utils::{create_dir, get_root_dir, get_work_dir, set_jobs},
+};
+use std::{
+    process::Command,
+    sync::{mpsc, Arc},
+    thread,
 };
-use std::{collections::HashMap, process::Command};
 
 const DOWNLOAD_DIR: &str = "downloads";
 const BUILD_DIR: &str = "builds";
@@ -21,42 +32,84 @@ const IMAGE_DIR: &str = "images";
 pub struct CrossDev {
     config: Config,
     work_dir: String,
-    packages: HashMap<PackType, Box<dyn Installable>>,
+    packages: Vec<Box<dyn Installable>>,
+    reporter: Arc<dyn Reporter>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum SetupState {
+    Pending,
+    Running,
+    Done,
+    Failed,
 }
 
 impl CrossDev {
     pub fn new(config: Config) -> Result<Self> {
+        Self::with_reporter(config, Arc::new(EmojiReporter))
+    }
+
+    /// Like `new`, with progress reported to `reporter` instead of printed as is
+    pub fn with_reporter(config: Config, reporter: Arc<dyn Reporter>) -> Result<Self> {
+        let xdev = Self::load_with_reporter(config, reporter)?;
+        xdev.reporting(|| {
+            create_dirs()?;
+
+            // The toolchain goes first since every other package needs the cross
+            // toolchain path it sets
+            xdev.packages[0].setup(false)
+        })?;
+
+        Ok(xdev)
+    }
+
+    /// Resolves the packages of `config` without downloading or building
+    /// anything, e.g. to report their status
+    pub fn load(config: Config) -> Result<Self> {
+        Self::load_with_reporter(config, Arc::new(EmojiReporter))
+    }
+
+    /// Like `load`, with progress reported to `reporter`
+    pub fn load_with_reporter(config: Config, reporter: Arc<dyn Reporter>) -> Result<Self> {
+        reporting_to(&reporter.clone(), || Self::load_packages(config, reporter))
+    }
+
+    fn load_packages(config: Config, reporter: Arc<dyn Reporter>) -> Result<Self> {
         let work_dir = format!("{}/{}", get_work_dir()?, config.name);
-        set_env(&work_dir)?;
+        set_env(&work_dir, config.build_type)?;
+        set_mirrors(config.mirrors.clone())?;
 
-        let mut packages: HashMap<PackType, Box<dyn Installable>> = HashMap::new();
+        let mut packages: Vec<Box<dyn Installable>> = Vec::new();
         let mut toolchain_package = None;
 
         for package in &config.packages {
-            // If the package is a toolchain, we will store it in a separate variable
-            // since we will need to use it to set the cross toolchain path
             if package.pack_type == PackType::Toolchain {
                 toolchain_package = Some(package.clone());
             } else {
-                packages.insert(package.pack_type, create_package(&config, package.clone())?);
+                packages.push(create_package(&config, package.clone())?);
             }
         }
 
-        if let Some(toolchain_package) = toolchain_package {
-            let mut toolchain = Toolchain::new(config.arch.clone(), toolchain_package)?;
-            toolchain.setup()?;
-            packages.insert(PackType::Toolchain, Box::new(toolchain));
-        } else {
-            return Err(CrossDevError::NoToolchainInConfig);
-        }
+        let toolchain_package = toolchain_package.ok_or(CrossDevError::NoToolchainInConfig)?;
+        packages.insert(0, create_package(&config, toolchain_package)?);
 
         Ok(Self {
             config,
             work_dir,
             packages,
+            reporter,
         })
     }
 
+    // Runs `run` with the events of this thread going to this setup's reporter
+    fn reporting<T>(&self, run: impl FnOnce() -> T) -> T {
+        reporting_to(&self.reporter, run)
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
     pub fn work_dir(&self) -> &str {
         &self.work_dir
     }
@@ -65,15 +118,180 @@ impl CrossDev {
         self.work_dir = work_dir;
     }
 
-    pub fn setup(&mut self) -> Result<()> {
-        for package in &mut self.packages {
-            if package.0 == &PackType::Toolchain {
-                continue;
+    /// Sets up every package in dependency order, skipping phases that are up to
+    /// date except for the packages in `force`. With `jobs`, independent packages
+    /// are built concurrently and share that many make jobs.
+    pub fn setup(&mut self, jobs: Option<usize>, force: &[String]) -> Result<()> {
+        self.reporting(|| {
+            for name in force {
+                self.find_package(name)?;
             }
-            package.1.setup()?;
-        }
+            let order = self.build_order()?;
 
-        Ok(())
+            // Dry runs record the steps in order, so they never run in parallel
+            match jobs.filter(|_| !is_dry_run()) {
+                None => {
+                    for index in order {
+                        let package = &self.packages[index];
+                        package.setup(force.iter().any(|name| name == package.name()))?;
+                    }
+                    Ok(())
+                }
+                Some(jobs) => self.setup_parallel(jobs, force),
+            }
+        })
+    }
+
+    /// Package names in the order `setup` builds them
+    pub fn setup_order(&self) -> Result<Vec<&str>> {
+        Ok(self
+            .build_order()?
+            .into_iter()
+            .map(|index| self.packages[index].name())
+            .collect())
+    }
+
+    // Indices of the packages each package depends on
+    fn dependency_graph(&self) -> Result<Vec<Vec<usize>>> {
+        let index_of = |name: &str| {
+            self.packages
+                .iter()
+                .position(|pkg| pkg.name() == name)
+                .ok_or_else(|| CrossDevError::PackageNotFound(name.to_string()))
+        };
+
+        self.packages
+            .iter()
+            .map(|package| {
+                let mut deps: Vec<usize> = package
+                    .dependencies()
+                    .into_iter()
+                    .filter_map(|pack_type| {
+                        self.packages
+                            .iter()
+                            .position(|pkg| pkg.pack_type() == pack_type)
+                    })
+                    .collect();
+
+                let depends_on = self
+                    .config
+                    .packages
+                    .iter()
+                    .filter(|pkg| pkg.name == package.name())
+                    .flat_map(|pkg| &pkg.depends_on);
+                for name in depends_on {
+                    deps.push(index_of(name)?);
+                }
+
+                Ok(deps)
+            })
+            .collect()
+    }
+
+    // Stable topological order: the first ready package in config order goes next
+    fn build_order(&self) -> Result<Vec<usize>> {
+        let deps = self.dependency_graph()?;
+        let done: Vec<bool> = self
+            .packages
+            .iter()
+            .map(|pkg| pkg.pack_type() == PackType::Toolchain)
+            .collect();
+
+        topological_order(&deps, done).map_err(|stuck| {
+            let cycle: Vec<_> = stuck
+                .into_iter()
+                .map(|index| self.packages[index].name())
+                .collect();
+            CrossDevError::DependencyCycle(cycle.join(", "))
+        })
+    }
+
+    fn setup_parallel(&self, jobs: usize, force: &[String]) -> Result<()> {
+        let deps = self.dependency_graph()?;
+        let mut states: Vec<SetupState> = self
+            .packages
+            .iter()
+            .map(|pkg| match pkg.pack_type() {
+                PackType::Toolchain => SetupState::Done,
+                _ => SetupState::Pending,
+            })
+            .collect();
+
+        let (tx, rx) = mpsc::channel();
+
+        thread::scope(|scope| {
+            // Jobs handed to each running package, given back when it finishes
+            let mut shares = vec![0; states.len()];
+            let mut free = jobs.max(1);
+            let mut running = 0;
+            let mut error = None;
+
+            loop {
+                // Stop starting new packages once one of them failed
+                if error.is_none() {
+                    let ready: Vec<usize> = (0..states.len())
+                        .filter(|&index| {
+                            states[index] == SetupState::Pending
+                                && deps[index]
+                                    .iter()
+                                    .all(|&dep| states[dep] == SetupState::Done)
+                        })
+                        .collect();
+
+                    // The free jobs are split between as many ready packages as
+                    // they stretch to, the others wait for a package to finish
+                    let split = split_jobs(free, ready.len());
+                    for (index, share) in ready.into_iter().zip(split) {
+                        let package = &self.packages[index];
+                        let force = force.iter().any(|name| name == package.name());
+                        let tx = tx.clone();
+
+                        info(format!("🧵 Starting {} with {share} jobs", package.name()));
+                        states[index] = SetupState::Running;
+                        shares[index] = share;
+                        free -= share;
+                        running += 1;
+
+                        scope.spawn(move || {
+                            set_jobs(Some(share));
+                            let result = self.reporting(|| package.setup(force));
+                            let _ = tx.send((index, result));
+                        });
+                    }
+                }
+
+                if running == 0 {
+                    break;
+                }
+
+                let (index, result) = rx.recv().expect("setup thread hung up");
+                running -= 1;
+                free += shares[index];
+
+                match result {
+                    Ok(()) => states[index] = SetupState::Done,
+                    Err(err) => {
+                        states[index] = SetupState::Failed;
+                        error.get_or_insert(err);
+                    }
+                }
+            }
+
+            if let Some(err) = error {
+                return Err(err);
+            }
+
+            // Anything still pending is stuck behind a dependency cycle
+            let stuck: Vec<_> = (0..states.len())
+                .filter(|&index| states[index] == SetupState::Pending)
+                .map(|index| self.packages[index].name())
+                .collect();
+            if !stuck.is_empty() {
+                return Err(CrossDevError::DependencyCycle(stuck.join(", ")));
+            }
+
+            Ok(())
+        })
     }
 
     pub fn run_qemu(&self, extra_args: Option<Vec<&str>>) -> Result<()> {
@@ -84,7 +302,7 @@ impl CrossDev {
 
         let image_dir = get_dir(EnvType::ImageDir)?;
 
-        let qemu_bin = qemu_pack.bin_path();
+        let qemu_bin = qemu_pack.bin_path()?;
         let opensbi_bin = format!("{image_dir}/{}", opensbi_pack.bin_name());
         let linux_bin = format!("{image_dir}/{}", linux_pack.bin_name());
         let rootfs_bin = format!("{image_dir}/{}", rootfs_pack.bin_name());
@@ -114,7 +332,7 @@ impl CrossDev {
             qemu_args.extend(extra);
         }
 
-        println!("{qemu_args:?}");
+        self.reporting(|| info(format!("{qemu_args:?}")));
 
         let mut child = Command::new(qemu_bin)
             .args(qemu_args)
@@ -126,25 +344,117 @@ impl CrossDev {
         Ok(())
     }
 
+    /// Moves one package (or all of them) to its latest upstream sources, or
+    /// back to its pinned tag or rev, and re-pins them in the lockfile
+    pub fn update(&self, name: Option<&str>) -> Result<()> {
+        self.reporting(|| {
+            let packages: Vec<&dyn Installable> = match name {
+                Some(name) => vec![self.find_package(name)?],
+                None => self.packages.iter().map(|pkg| pkg.as_ref()).collect(),
+            };
+
+            for package in packages {
+                info(format!("🔄 Updating package: {}", package.name()));
+                package.update()?;
+                package.patch()?;
+                info(format!(
+                    "✅ Package {} updated, run setup to rebuild it",
+                    package.name()
+                ));
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Removes the build output of one package (or all of them), and with
+    /// `sources` their downloaded sources too
+    pub fn clean(&self, name: Option<&str>, sources: bool) -> Result<()> {
+        self.reporting(|| {
+            let packages: Vec<&dyn Installable> = match name {
+                Some(name) => vec![self.find_package(name)?],
+                None => self.packages.iter().map(|pkg| pkg.as_ref()).collect(),
+            };
+
+            for package in packages {
+                info(format!("🧹 Cleaning package: {}", package.name()));
+                if sources {
+                    package.distclean()?;
+                } else {
+                    package.clean()?;
+                }
+                remove_stamps(package.name())?;
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Download, build and install state of every package
+    pub fn status(&self) -> Result<Status<'static>> {
+        self.reporting(|| {
+            let env = ENV_VARS.get().ok_or(CrossDevError::EnvVarsError)?;
+            let packages = self
+                .packages
+                .iter()
+                .map(|package| {
+                    let version = self
+                        .config
+                        .packages
+                        .iter()
+                        .find(|pkg| pkg.name == package.name())
+                        .map_or("", |pkg| pkg.version.as_str());
+                    PackageStatus::new(package.as_ref(), version)
+                })
+                .collect::<Result<_>>()?;
+
+            Ok(Status { env, packages })
+        })
+    }
+
+    /// Packs everything this profile downloaded into a tar file for offline setups
+    pub fn export_bundle(&self, path: &str) -> Result<()> {
+        self.reporting(|| export_bundle(&self.config, path))
+    }
+
+    /// Seeds this profile's downloads, the download cache and the lockfile from
+    /// a bundle made by `export_bundle`
+    pub fn import_bundle(&self, path: &str) -> Result<Option<Manifest>> {
+        self.reporting(|| {
+            create_dirs()?;
+            import_bundle(&self.config, path)
+        })
+    }
+
+    /// Sets up the package of `pack_type` on its own, e.g. after editing its sources
+    pub fn setup_package(&self, pack_type: PackType) -> Result<()> {
+        self.reporting(|| self.get_package(pack_type)?.setup(false))
+    }
+
+    pub fn find_package(&self, name: &str) -> Result<&dyn Installable> {
+        self.packages
+            .iter()
+            .find(|pkg| pkg.name() == name)
+            .map(|pkg| pkg.as_ref())
+            .ok_or_else(|| CrossDevError::PackageNotFound(name.to_string()))
+    }
+
     pub fn get_package(&self, pack_type: PackType) -> Result<&dyn Installable> {
         self.packages
-            .get(&pack_type)
+            .iter()
+            .find(|pkg| pkg.pack_type() == pack_type)
             .map(|pkg| pkg.as_ref())
             .ok_or(CrossDevError::PackageError(pack_type))
     }
 }
 
-fn set_env(work_dir: &str) -> Result<()> {
+fn set_env(work_dir: &str, build_type: BuildType) -> Result<()> {
     let root_dir = get_root_dir()?;
     let work_dir = work_dir.to_string();
     let download_dir = format!("{work_dir}/{DOWNLOAD_DIR}");
-    let build_dir = format!("{work_dir}/{BUILD_DIR}");
-    let image_dir = format!("{work_dir}/{IMAGE_DIR}");
-
-    create_dir(&work_dir)?;
-    create_dir(&download_dir)?;
-    create_dir(&build_dir)?;
-    create_dir(&image_dir)?;
+    // Debug and release outputs live side by side under the same profile
+    let build_dir = format!("{work_dir}/{BUILD_DIR}/{build_type}");
+    let image_dir = format!("{work_dir}/{IMAGE_DIR}/{build_type}");
 
     set_env_vars(EnvVars::new(
         root_dir,
@@ -157,14 +467,114 @@ fn set_env(work_dir: &str) -> Result<()> {
     Ok(())
 }
 
+fn create_dirs() -> Result<()> {
+    for env_type in [
+        EnvType::WorkDir,
+        EnvType::DownloadDir,
+        EnvType::BuildDir,
+        EnvType::ImageDir,
+    ] {
+        let dir = get_dir(env_type)?;
+        if is_dry_run() {
+            make_dir(&dir)?;
+        } else {
+            create_dir(&dir)?;
+        }
+    }
+
+    Ok(())
+}
+
+// Stable topological order of the packages not `done` yet, or the indices of
+// those stuck behind a dependency cycle
+fn topological_order(
+    deps: &[Vec<usize>],
+    mut done: Vec<bool>,
+) -> std::result::Result<Vec<usize>, Vec<usize>> {
+    let mut order = Vec::new();
+
+    while let Some(pending) = done.iter().position(|done| !done) {
+        let ready = (pending..done.len())
+            .find(|&index| !done[index] && deps[index].iter().all(|&dep| done[dep]));
+
+        let Some(index) = ready else {
+            return Err((0..done.len()).filter(|&index| !done[index]).collect());
+        };
+
+        done[index] = true;
+        order.push(index);
+    }
+
+    Ok(order)
+}
+
+// Shares of `free` jobs for up to `ready` packages, at least one job each
+fn split_jobs(free: usize, ready: usize) -> Vec<usize> {
+    let count = free.min(ready);
+    if count == 0 {
+        return Vec::new();
+    }
+
+    (0..count)
+        .map(|index| free / count + usize::from(index < free % count))
+        .collect()
+}
+
 fn create_package(config: &Config, package: Package) -> Result<Box<dyn Installable>> {
     let arch = config.arch.clone();
+    let build_type = config.build_type;
+    let flavor = config.toolchain_flavor;
 
     match package.pack_type {
-        PackType::Toolchain => Ok(Box::new(Toolchain::new(arch, package)?)),
-        PackType::Qemu => Ok(Box::new(Qemu::new(arch, package)?)),
-        PackType::Opensbi => Ok(Box::new(Opensbi::new(arch, package)?)),
-        PackType::Linux => Ok(Box::new(Linux::new(arch, package)?)),
-        PackType::Buildroot => Ok(Box::new(Buildroot::new(arch, package)?)),
+        PackType::Toolchain => Ok(Box::new(Toolchain::new(arch, flavor, package)?)),
+        PackType::Qemu => Ok(Box::new(Qemu::new(arch, build_type, package)?)),
+        PackType::Opensbi => Ok(Box::new(Opensbi::new(arch, build_type, flavor, package)?)),
+        PackType::Linux => Ok(Box::new(Linux::new(
+            arch,
+            build_type,
+            config.sanitizers.clone(),
+            flavor,
+            package,
+        )?)),
+        PackType::Buildroot => Ok(Box::new(Buildroot::new(arch, build_type, flavor, package)?)),
+        PackType::Custom => Ok(Box::new(Custom::new(arch, build_type, flavor, package)?)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_config_order_between_independent_packages() {
+        // 0: toolchain, 1: qemu, 2: opensbi -> 3, 3: linux, 4: buildroot
+        let deps = vec![vec![], vec![], vec![3], vec![], vec![]];
+        let done = vec![true, false, false, false, false];
+
+        assert_eq!(topological_order(&deps, done), Ok(vec![1, 3, 2, 4]));
+    }
+
+    #[test]
+    fn follows_chains_of_dependencies() {
+        let deps = vec![vec![1], vec![2], vec![]];
+
+        assert_eq!(topological_order(&deps, vec![false; 3]), Ok(vec![2, 1, 0]));
+    }
+
+    #[test]
+    fn reports_the_packages_stuck_in_a_cycle() {
+        let deps = vec![vec![], vec![2], vec![1], vec![1]];
+
+        assert_eq!(topological_order(&deps, vec![false; 4]), Err(vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn never_hands_out_more_jobs_than_there_are() {
+        assert_eq!(split_jobs(8, 3), [3, 3, 2]);
+        assert_eq!(split_jobs(8, 1), [8]);
+        assert_eq!(split_jobs(1, 3), [1]);
+        assert_eq!(split_jobs(2, 3), [1, 1]);
+        assert!(split_jobs(0, 2).is_empty());
+        assert!(split_jobs(4, 0).is_empty());
     }
 }
@@ -0,0 +1,269 @@
+use crate::{
+    config::*,
+    download::*,
+    env::*,
+    error::Result,
+    patch::apply_patches,
+    reporter::info,
+    runner::{copy_file, is_dry_run, make_dir, RunLogged},
+    stamp::*,
+    system::check_system,
+    traits::Installable,
+    utils::*,
+};
+use std::path::Path;
+use xshell::{cmd, Shell};
+
+/// A package built and installed by the shell steps of its `script` table
+#[derive(Debug)]
+pub struct Custom {
+    arch: String,
+    build_type: BuildType,
+    flavor: ToolchainFlavor,
+    package: Package,
+    build_dir: String,
+    source_dir: String,
+    bin_name: String,
+    bin_path: String,
+}
+
+impl Custom {
+    pub fn new(
+        arch: String,
+        build_type: BuildType,
+        flavor: ToolchainFlavor,
+        package: Package,
+    ) -> Result<Self> {
+        let build_dir = format!("{}/{}", get_dir(EnvType::BuildDir)?, package.name);
+        let source_dir = source_dir(&package.download, &package.name)?;
+
+        // The first artifact stands for the package, like `Image` does for Linux
+        let (bin_name, bin_path) = match package.script.artifacts.first() {
+            Some(artifact) => {
+                let bin_path = artifact_path(&build_dir, artifact);
+                (file_name(&bin_path), bin_path)
+            }
+            None => (String::new(), String::new()),
+        };
+
+        Ok(Self {
+            arch,
+            build_type,
+            flavor,
+            package,
+            build_dir,
+            source_dir,
+            bin_name,
+            bin_path,
+        })
+    }
+
+    // Shell for the script steps, with the toolchain and every directory exported
+    fn shell(&self) -> Result<Shell> {
+        let mut sh = Shell::new()?;
+        make_dir(&self.build_dir)?;
+        sh.set_current_dir(&self.build_dir);
+
+        // Like the kernel's, an LLVM build finds clang on PATH and picks its
+        // target from ARCH, which a CROSS_COMPILE prefix would override
+        match self.flavor {
+            ToolchainFlavor::Gnu => sh.set_var("CROSS_COMPILE", get_cross_toolchain_path()?),
+            ToolchainFlavor::Llvm => {
+                sh.set_var("LLVM", "1");
+                sh.set_var("PATH", llvm_search_path()?);
+            }
+        }
+
+        let envs = [
+            ("TOOLCHAIN_FLAVOR", self.flavor.to_string()),
+            ("ARCH", kernel_arch(&self.arch).to_string()),
+            ("BUILD_TYPE", self.build_type.to_string()),
+            ("NAME", self.package.name.clone()),
+            ("VERSION", self.package.version.clone()),
+            ("JOBS", get_jobs()?),
+            ("ROOT_DIR", get_dir(EnvType::RootDir)?),
+            ("WORK_DIR", get_dir(EnvType::WorkDir)?),
+            ("DOWNLOAD_DIR", get_dir(EnvType::DownloadDir)?),
+            ("BUILD_DIR", get_dir(EnvType::BuildDir)?),
+            ("IMAGE_DIR", get_dir(EnvType::ImageDir)?),
+            ("SOURCE_DIR", self.source_dir.clone()),
+            ("PACKAGE_BUILD_DIR", self.build_dir.clone()),
+        ];
+
+        for (k, v) in envs {
+            sh.set_var(k, v)
+        }
+        self.package.build.apply_env(&mut sh);
+
+        Ok(sh)
+    }
+
+    fn run_steps(&self, steps: &[String]) -> Result<()> {
+        let sh = self.shell()?;
+
+        for step in steps {
+            cmd!(sh, "sh -c {step}").run_logged()?;
+        }
+
+        Ok(())
+    }
+
+    // Artifacts paired with where they are installed
+    fn artifacts(&self) -> Result<Vec<(String, String)>> {
+        let image_dir = get_dir(EnvType::ImageDir)?;
+
+        Ok(self
+            .package
+            .script
+            .artifacts
+            .iter()
+            .map(|artifact| {
+                let path = artifact_path(&self.build_dir, artifact);
+                let image_path = format!("{image_dir}/{}", file_name(&path));
+                (path, image_path)
+            })
+            .collect())
+    }
+}
+
+impl Installable for Custom {
+    fn name(&self) -> &str {
+        &self.package.name
+    }
+
+    fn pack_type(&self) -> PackType {
+        self.package.pack_type
+    }
+
+    fn download(&self) -> Result<()> {
+        match &self.package.download {
+            Download::Git(git) => git_clone(self.name(), git, &self.source_dir)?,
+            Download::File(file) => {
+                if is_dry_run() || !Path::new(&self.source_dir).exists() {
+                    extract_file(self.name(), file, &self.source_dir)?;
+                }
+            }
+            Download::Apt(apt) => {
+                let package_name = &apt.package_name;
+                ensure_online(self.name(), &format!("apt package {package_name}"))?;
+                info(format!("📦 Installing {package_name} via APT..."));
+                let sh = Shell::new()?;
+                cmd!(sh, "sudo apt install -y {package_name}").run_interactive()?;
+            }
+            Download::Path(local) => check_local_source(local)?,
+            // A host tool the script relies on, there are no sources
+            Download::System(system) => {
+                check_system(self.name(), system, &[self.name().to_string()])?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn patch(&self) -> Result<()> {
+        apply_patches(&self.package, &self.source_dir)
+    }
+
+    fn update(&self) -> Result<()> {
+        match &self.package.download {
+            Download::Git(git) => git_update(self.name(), git, &self.source_dir),
+            Download::File(file) => update_file(self.name(), file, &self.source_dir),
+            _ => self.download(),
+        }
+    }
+
+    fn build(&self) -> Result<()> {
+        self.run_steps(&self.package.script.build)
+    }
+
+    fn install(&self) -> Result<()> {
+        self.run_steps(&self.package.script.install)?;
+
+        for (path, image_path) in self.artifacts()? {
+            copy_file(&path, &image_path)?;
+        }
+
+        Ok(())
+    }
+
+    fn clean(&self) -> Result<()> {
+        remove_dir(&self.build_dir)
+    }
+
+    fn distclean(&self) -> Result<()> {
+        self.clean()?;
+        remove_sources(&self.package.download, &self.source_dir)
+    }
+
+    fn fingerprint(&self, phase: Phase) -> Result<Option<Fingerprint>> {
+        let download = &self.package.download;
+
+        match phase {
+            Phase::Download => download_fingerprint(&self.package, &self.source_dir),
+            Phase::Build => {
+                let Some(revision) = source_revision(download, &self.source_dir)? else {
+                    return Ok(None);
+                };
+
+                let mut fingerprint = Fingerprint::new()
+                    .add("package", format!("{:?}", self.package))
+                    .add("revision", revision)
+                    .add("arch", &self.arch)
+                    .add("build_type", self.build_type)
+                    .add("toolchain", get_cross_toolchain_path()?)
+                    .add("flavor", self.flavor);
+                for (path, _) in self.artifacts()? {
+                    fingerprint = fingerprint.add("output", file_signature(&path));
+                }
+
+                Ok(Some(fingerprint))
+            }
+            Phase::Install => {
+                let mut fingerprint = Fingerprint::new();
+                for (path, image_path) in self.artifacts()? {
+                    fingerprint = fingerprint
+                        .add("binary", file_signature(&path))
+                        .add("image", file_signature(&image_path));
+                }
+
+                Ok(Some(fingerprint))
+            }
+        }
+    }
+
+    fn image_path(&self) -> Result<Option<String>> {
+        let image_dir = get_dir(EnvType::ImageDir)?;
+        Ok((!self.bin_name.is_empty()).then(|| format!("{image_dir}/{}", self.bin_name)))
+    }
+
+    fn source_dir(&self) -> &str {
+        &self.source_dir
+    }
+
+    fn build_dir(&self) -> &str {
+        &self.build_dir
+    }
+
+    fn bin_name(&self) -> &str {
+        &self.bin_name
+    }
+
+    fn bin_path(&self) -> Result<&str> {
+        Ok(&self.bin_path)
+    }
+}
+
+fn artifact_path(build_dir: &str, artifact: &str) -> String {
+    if Path::new(artifact).is_absolute() {
+        artifact.to_string()
+    } else {
+        format!("{build_dir}/{artifact}")
+    }
+}
+
+fn file_name(path: &str) -> String {
+    Path::new(path)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
@@ -0,0 +1,535 @@
+use crate::{
+    archive,
+    cache::{cached_path, evict_entry, record_entry, touch_entry, LOCK_SUFFIX},
+    config::*,
+    env::*,
+    error::*,
+    http::{Downloader, Progress},
+    lock::*,
+    mirror::{candidate_urls, try_mirrors},
+    patch::{applied_patches, patches_fingerprint, upstream_head},
+    reporter::{info, report, Event},
+    runner::{
+        current_package, extract_archive, is_dry_run, is_offline, make_dir, quote, RunLogged,
+    },
+    stamp::Fingerprint,
+    utils::{expand_home, remove_dir},
+};
+use sha2::{Digest, Sha256, Sha512};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+use xshell::{cmd, Shell};
+
+// Bytes between progress reports of a download of unknown size
+const PROGRESS_STEP: u64 = 16 * 1024 * 1024;
+
+// Leading directories dropped from archives that don't say otherwise
+const DEFAULT_STRIP_COMPONENTS: usize = 1;
+
+/// Directory holding a package's sources: the user's own tree for `path`
+/// downloads, `<download_dir>/<extract_dir>` for archives that set it and
+/// `<download_dir>/<dir_name>` otherwise
+pub fn source_dir(download: &Download, dir_name: &str) -> Result<String> {
+    match download {
+        Download::Path(local) => expand_home(&local.path),
+        Download::File(FileDownload {
+            extract_dir: Some(extract_dir),
+            ..
+        }) => Ok(format!("{}/{extract_dir}", get_dir(EnvType::DownloadDir)?)),
+        _ => Ok(format!("{}/{dir_name}", get_dir(EnvType::DownloadDir)?)),
+    }
+}
+
+/// Makes sure a `path` download points at an existing source tree
+pub fn check_local_source(local: &PathDownload) -> Result<()> {
+    let path = expand_home(&local.path)?;
+
+    if !Path::new(&path).is_dir() {
+        return Err(CrossDevError::DirectoryNotFound(path));
+    }
+    info(format!("📂 Using local sources at {path}"));
+
+    Ok(())
+}
+
+/// Fails in offline mode, for steps that would have to reach `url`
+pub fn ensure_online(name: &str, url: &str) -> Result<()> {
+    if is_offline() && !is_dry_run() {
+        return Err(CrossDevError::Offline {
+            package: name.to_string(),
+            url: url.to_string(),
+        });
+    }
+
+    Ok(())
+}
+
+/// Deletes downloaded sources; a local `path` tree belongs to the user and is
+/// kept, and a `system` tool has none
+pub fn remove_sources(download: &Download, dir: &str) -> Result<()> {
+    match download {
+        Download::Path(_) => info(format!("📂 Keeping local sources at {dir}")),
+        Download::System(_) => {}
+        _ => remove_dir(dir)?,
+    }
+
+    Ok(())
+}
+
+/// Clones `git` into `dest` unless it already exists, then checks out its `rev`
+/// or the commit pinned in the lockfile and records the resolved commit
+pub fn git_clone(name: &str, git: &GitDownload, dest: &str) -> Result<()> {
+    let sh = Shell::new()?;
+    let repo = git.url.as_str();
+
+    // A dry run records the clone either way, the script starts from scratch
+    if is_dry_run() || !sh.path_exists(dest) {
+        ensure_online(name, repo)?;
+
+        let mut args = Vec::new();
+        if git.depth > 0 {
+            args.extend(["--depth".to_string(), git.depth.to_string()]);
+        }
+        if let Some(branch) = git.tag.as_ref().or(git.branch.as_ref()) {
+            args.extend([
+                "-b".to_string(),
+                branch.clone(),
+                "--single-branch".to_string(),
+            ]);
+        }
+        if git.submodules {
+            args.push("--recurse-submodules".to_string());
+            if git.depth > 0 {
+                args.push("--shallow-submodules".to_string());
+            }
+        }
+
+        try_mirrors(repo, &git.mirrors, |url| {
+            let args = &args;
+            info(format!("📦 Cloning {url} ({})...", git_ref(git)));
+            cmd!(sh, "git clone {args...} {url} {dest}").run_interactive()
+        })?;
+    }
+
+    // The config's own `rev` wins over whatever the lockfile remembers
+    let pinned = git
+        .rev
+        .clone()
+        .or_else(|| locked_source(name, repo).and_then(|source| source.commit));
+    if let Some(commit) = pinned {
+        // The script checks the pin out after its fresh clone
+        let head = if !is_dry_run() && sh.path_exists(dest) {
+            Some(upstream_head(dest)?)
+        } else {
+            None
+        };
+        if head.as_ref() != Some(&commit) {
+            info(format!("📌 Checking out {commit}"));
+            ensure_online(name, repo)?;
+            git_fetch(git, dest, &commit)?;
+            cmd!(sh, "git -C {dest} checkout --detach FETCH_HEAD").run_logged()?;
+            update_submodules(git, dest)?;
+        }
+    }
+
+    lock_git(name, repo, dest)
+}
+
+/// Brings an existing checkout up to date: fast-forwards a followed branch, or
+/// checks out the configured tag or `rev` again, then re-pins it. Local
+/// modifications are never overwritten.
+pub fn git_update(name: &str, git: &GitDownload, dest: &str) -> Result<()> {
+    let sh = Shell::new()?;
+    let repo = git.url.as_str();
+
+    ensure_online(name, repo)?;
+
+    if !sh.path_exists(dest) {
+        unlock_source(name)?;
+        return git_clone(name, git, dest);
+    }
+
+    let changes = cmd!(sh, "git -C {dest} status --porcelain --untracked-files=no").read()?;
+    if !changes.trim().is_empty() {
+        return Err(CrossDevError::DirtyCheckout {
+            package: name.to_string(),
+            dir: dest.to_string(),
+        });
+    }
+
+    // Patches are applied again on top of the new sources
+    let upstream = upstream_head(dest)?;
+    if upstream != git_head(dest)? {
+        cmd!(sh, "git -C {dest} checkout --detach {upstream}").run_logged()?;
+    }
+
+    unlock_source(name)?;
+    info(format!("🔄 Updating {repo} ({})...", git_ref(git)));
+
+    match (&git.rev, &git.tag, &git.branch) {
+        (Some(rev), _, _) => git_fetch(git, dest, rev)?,
+        (None, Some(tag), _) => git_fetch(git, dest, &format!("refs/tags/{tag}"))?,
+        (None, None, branch) => {
+            git_fetch(git, dest, branch.as_deref().unwrap_or("HEAD"))?;
+        }
+    }
+
+    match (&git.rev, &git.tag, &git.branch) {
+        (None, None, Some(branch)) if git.depth == 0 => {
+            cmd!(sh, "git -C {dest} checkout -B {branch}").run_logged()?;
+            cmd!(sh, "git -C {dest} merge --ff-only FETCH_HEAD").run_logged()?;
+        }
+        // A shallow history has nothing to fast-forward from
+        (None, None, Some(branch)) => {
+            cmd!(sh, "git -C {dest} checkout -B {branch} FETCH_HEAD").run_logged()?;
+        }
+        _ => cmd!(sh, "git -C {dest} checkout --detach FETCH_HEAD").run_logged()?,
+    }
+    update_submodules(git, dest)?;
+
+    lock_git(name, repo, dest)
+}
+
+// Fetches `refspec` from the first of the repository's mirrors that has it, no
+// deeper than the configured depth
+fn git_fetch(git: &GitDownload, dest: &str, refspec: &str) -> Result<()> {
+    let sh = Shell::new()?;
+    let depth = match git.depth {
+        0 => Vec::new(),
+        depth => vec![format!("--depth={depth}")],
+    };
+
+    try_mirrors(&git.url, &git.mirrors, |url| {
+        let depth = &depth;
+        cmd!(sh, "git -C {dest} fetch {depth...} {url} {refspec}").run_logged()
+    })
+}
+
+fn update_submodules(git: &GitDownload, dest: &str) -> Result<()> {
+    if !git.submodules {
+        return Ok(());
+    }
+
+    let sh = Shell::new()?;
+    let depth = match git.depth {
+        0 => Vec::new(),
+        depth => vec![format!("--depth={depth}")],
+    };
+    cmd!(
+        sh,
+        "git -C {dest} submodule update --init --recursive {depth...}"
+    )
+    .run_logged()?;
+
+    Ok(())
+}
+
+// What `git` checks out, for messages
+fn git_ref(git: &GitDownload) -> String {
+    match (&git.rev, &git.tag, &git.branch) {
+        (Some(rev), _, _) => format!("rev {rev}"),
+        (None, Some(tag), _) => format!("tag {tag}"),
+        (None, None, Some(branch)) => format!("branch {branch}"),
+        (None, None, None) => "default branch".to_string(),
+    }
+}
+
+/// Downloads `file` into the shared cache unless it is there already, verifies
+/// it against the configured checksums and the lockfile pin, and returns the
+/// path of the cached file. A cached copy failing verification is evicted and
+/// downloaded once more.
+pub fn fetch_file(name: &str, file: &FileDownload) -> Result<String> {
+    let sh = Shell::new()?;
+    let url = file.url.as_str();
+    let path = cached_path(file)?;
+    let entry_dir = Path::new(&path).parent().unwrap().display().to_string();
+
+    // The script can't use the downloader, so it falls back to curl
+    if is_dry_run() {
+        make_dir(&entry_dir)?;
+        let fetch: Vec<_> = candidate_urls(url, &file.mirrors)
+            .iter()
+            .map(|url| format!("curl -fL --retry 5 -C - -o {} {}", quote(&path), quote(url)))
+            .collect();
+        let fetch = fetch.join(" || ");
+        cmd!(sh, "sh -c {fetch}").run_logged()?;
+        return dry_run_checksums(name, file, &path);
+    }
+
+    let mut cached = Path::new(&path).exists();
+    let sha256 = loop {
+        if cached {
+            info(format!("♻️  Reusing cached {url}"));
+        } else {
+            ensure_online(name, url)?;
+            make_dir(&entry_dir)?;
+            download_file(file, &path)?;
+        }
+
+        match verify_file(name, file, &path) {
+            Ok(sha256) => break sha256,
+            // A corrupt cached copy gets one fresh download before giving up
+            Err(
+                CrossDevError::ChecksumMismatch { .. }
+                | CrossDevError::LockedChecksumMismatch { .. },
+            ) if cached => {
+                info(format!("🗑️  Cached copy of {url} is damaged, evicting it"));
+                evict_entry(file)?;
+                cached = false;
+            }
+            Err(err) => return Err(err),
+        }
+    };
+
+    record_entry(file, &path, &sha256)?;
+    lock_source(
+        name,
+        LockedSource {
+            url: url.to_string(),
+            commit: None,
+            sha256: Some(sha256),
+        },
+    )?;
+
+    Ok(path)
+}
+
+/// Downloads the archive `file` and extracts it into `dest`
+pub fn extract_file(name: &str, file: &FileDownload, dest: &str) -> Result<()> {
+    let archive = fetch_file(name, file)?;
+    let strip_components = file.strip_components.unwrap_or(DEFAULT_STRIP_COMPONENTS);
+
+    extract_archive(&archive, dest, strip_components)
+}
+
+// Downloads `file` to `path` in the cache. The downloader only renames its
+// `.partial` file to `path` once complete, so a later run can resume it; other
+// profiles may share the cache, so a lock keeps two runs from writing it at once.
+fn download_file(file: &FileDownload, path: &str) -> Result<()> {
+    let url = file.url.as_str();
+    let package = current_package();
+
+    let lock = fs::File::create(format!("{path}{LOCK_SUFFIX}"))?;
+    lock.lock()?;
+    // Another run may have finished the download while this one waited
+    if Path::new(path).exists() {
+        return Ok(());
+    }
+
+    try_mirrors(url, &file.mirrors, |url| {
+        info(format!("📦 Downloading {url}..."));
+        let reported = AtomicU64::new(0);
+        let downloader = Downloader::new().on_progress(|progress| {
+            if should_report(&progress, reported.load(Ordering::Relaxed)) {
+                reported.store(progress.downloaded, Ordering::Relaxed);
+                report(Event::DownloadProgress {
+                    package: package.as_deref(),
+                    url,
+                    downloaded: progress.downloaded,
+                    total: progress.total,
+                });
+            }
+        });
+        downloader.download(url, path)
+    })
+}
+
+// Checks `path` against the configured checksums and the lockfile pin,
+// removing it on a mismatch, and returns its sha256
+fn verify_file(name: &str, file: &FileDownload, path: &str) -> Result<String> {
+    let sha256 = sha256_file(path)?;
+    verify_checksum(path, "sha256", file.sha256.as_deref(), &sha256)?;
+
+    if let Some(expected) = &file.sha512 {
+        let sha512 = digest_file::<Sha512>(path)?;
+        verify_checksum(path, "sha512", Some(expected), &sha512)?;
+    }
+
+    if let Some(expected) = locked_source(name, &file.url).and_then(|source| source.sha256) {
+        if expected != sha256 {
+            fs::remove_file(path)?;
+            return Err(CrossDevError::LockedChecksumMismatch {
+                package: name.to_string(),
+                expected,
+                actual: sha256,
+            });
+        }
+    }
+
+    Ok(sha256)
+}
+
+/// The files extracting `file` creates, relative to its destination; `None`
+/// while the archive isn't in the cache, e.g. in a dry run of a first setup
+pub fn archive_files(file: &FileDownload) -> Result<Option<Vec<PathBuf>>> {
+    let archive = cached_path(file)?;
+    if !Path::new(&archive).exists() {
+        return Ok(None);
+    }
+    let strip_components = file.strip_components.unwrap_or(DEFAULT_STRIP_COMPONENTS);
+
+    Ok(Some(archive::list(&archive, strip_components)?))
+}
+
+/// Downloads `file` again, bypassing the cache and the lockfile pin, and
+/// extracts it into `dest` in place of the old sources
+pub fn update_file(name: &str, file: &FileDownload, dest: &str) -> Result<()> {
+    ensure_online(name, &file.url)?;
+    info(format!("🔄 Fetching {} again...", file.url));
+
+    if !is_dry_run() {
+        unlock_source(name)?;
+        evict_entry(file)?;
+        remove_dir(dest)?;
+    }
+
+    extract_file(name, file, dest)
+}
+
+/// Identifies the sources in `dir`, including local edits to a git checkout.
+/// `None` for a plain local tree, whose changes can't be tracked cheaply.
+pub fn source_revision(download: &Download, dir: &str) -> Result<Option<String>> {
+    if Path::new(&format!("{dir}/.git")).exists() {
+        let sh = Shell::new()?;
+        let status = cmd!(sh, "git -C {dir} status --porcelain").read()?;
+        let diff = cmd!(sh, "git -C {dir} diff HEAD").read()?;
+        let changes = Fingerprint::new()
+            .add("status", status)
+            .add("diff", diff)
+            .digest();
+
+        return Ok(Some(format!("{}+{changes}", git_head(dir)?)));
+    }
+
+    Ok(match download {
+        Download::Git(_) => Some("missing".to_string()),
+        Download::File(_) if !Path::new(dir).exists() => Some("missing".to_string()),
+        Download::File(file) => Some(match applied_patches(dir) {
+            Some(patches) => format!("{}+{patches}", file.url),
+            None => file.url.clone(),
+        }),
+        Download::Apt(apt) => Some(apt.package_name.clone()),
+        Download::Path(_) => None,
+        Download::System(system) => Some(format!("{system:?}")),
+    })
+}
+
+/// Inputs of the download phase: the configured source, its lockfile pin,
+/// the patches applied on top and what is currently checked out in `dir`
+pub fn download_fingerprint(package: &Package, dir: &str) -> Result<Option<Fingerprint>> {
+    let (name, download) = (package.name.as_str(), &package.download);
+    let url = match download {
+        Download::Git(git) => git.url.as_str(),
+        Download::File(file) => {
+            // Every setup relying on the cached archive uses it, even one
+            // whose download phase is skipped
+            touch_entry(file)?;
+            file.url.as_str()
+        }
+        _ => "",
+    };
+
+    Ok(Some(
+        Fingerprint::new()
+            .add("download", format!("{download:?}"))
+            .add("locked", format!("{:?}", locked_source(name, url)))
+            .add("patches", patches_fingerprint(package)?)
+            .add("revision", format!("{:?}", source_revision(download, dir)?)),
+    ))
+}
+
+pub fn sha256_file(path: &str) -> Result<String> {
+    digest_file::<Sha256>(path)
+}
+
+fn digest_file<D: Digest + io::Write>(path: &str) -> Result<String> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = D::new();
+    io::copy(&mut file, &mut hasher)?;
+
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect())
+}
+
+// A corrupt download is deleted so the next run fetches it again
+fn verify_checksum(
+    path: &str,
+    algorithm: &str,
+    expected: Option<&str>,
+    actual: &str,
+) -> Result<()> {
+    match expected {
+        Some(expected) if !expected.eq_ignore_ascii_case(actual) => {
+            info(format!("❌ {algorithm} mismatch, removing {path}"));
+            fs::remove_file(path)?;
+
+            Err(CrossDevError::ChecksumMismatch {
+                file: path.to_string(),
+                algorithm: algorithm.to_string(),
+                expected: expected.to_string(),
+                actual: actual.to_string(),
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+pub fn git_head(dir: &str) -> Result<String> {
+    let sh = Shell::new()?;
+    let head = cmd!(sh, "git -C {dir} rev-parse HEAD").read()?;
+
+    Ok(head.trim().to_string())
+}
+
+// Nothing was downloaded in a dry run, so the script verifies the checksums itself
+fn dry_run_checksums(name: &str, file: &FileDownload, path: &str) -> Result<String> {
+    let sh = Shell::new()?;
+    let locked = locked_source(name, &file.url).and_then(|source| source.sha256);
+
+    let checks = [
+        ("sha256sum", file.sha256.as_ref()),
+        ("sha512sum", file.sha512.as_ref()),
+        ("sha256sum", locked.as_ref()),
+    ];
+    for (tool, expected) in checks {
+        if let Some(expected) = expected {
+            let line = format!("{expected}  {path}");
+            let check = format!("echo {} | {tool} -c -", quote(&line));
+            cmd!(sh, "sh -c {check}").run_logged()?;
+        }
+    }
+
+    Ok(path.to_string())
+}
+
+// Every tenth of the file, or every few MiB when its size is unknown
+fn should_report(progress: &Progress, reported: u64) -> bool {
+    match progress.total {
+        Some(total) => {
+            progress.downloaded == total
+                || progress.downloaded * 10 / total.max(1) > reported * 10 / total.max(1)
+        }
+        None => progress.downloaded - reported >= PROGRESS_STEP,
+    }
+}
+
+fn lock_git(name: &str, url: &str, dir: &str) -> Result<()> {
+    if is_dry_run() {
+        return Ok(());
+    }
+
+    lock_source(
+        name,
+        LockedSource {
+            url: url.to_string(),
+            commit: Some(upstream_head(dir)?),
+            sha256: None,
+        },
+    )
+}
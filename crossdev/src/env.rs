@@ -1,4 +1,5 @@
 use crate::error::*;
+use serde::Serialize;
 use std::sync::OnceLock;
 
 #[derive(Debug)]
@@ -10,7 +11,7 @@ pub enum EnvType {
     ImageDir,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct EnvVars {
     pub root_dir: String,
     pub work_dir: String,
@@ -59,6 +60,23 @@ impl EnvVars {
 
 pub static ENV_VARS: OnceLock<EnvVars> = OnceLock::new();
 
+/// Directories for unit tests, below one temporary root. They are global, so
+/// every test shares them and works in paths of its own.
+#[cfg(test)]
+pub(crate) fn test_env_vars() -> &'static EnvVars {
+    ENV_VARS.get_or_init(|| {
+        let root = std::env::temp_dir().join(format!("crossdev-test-{}", std::process::id()));
+        let root = root.display().to_string();
+        EnvVars::new(
+            root.clone(),
+            format!("{root}/work"),
+            format!("{root}/downloads"),
+            format!("{root}/builds"),
+            format!("{root}/images"),
+        )
+    })
+}
+
 pub fn set_env_vars(env_vars: EnvVars) -> Result<()> {
     ENV_VARS
         .set(env_vars)
@@ -28,6 +28,9 @@ pub enum CrossDevError {
     #[error("Failed to find gcc from toolchain")]
     GccNotFound,
 
+    #[error("Failed to find clang from toolchain")]
+    ClangNotFound,
+
     #[error("Failed to get HOME directory")]
     HomeDirError(#[from] std::env::VarError),
 
@@ -57,6 +60,125 @@ pub enum CrossDevError {
 
     #[error("Directory not created: {0}")]
     DirectoryNotCreated(String),
+
+    #[error("Package not found: {0}")]
+    PackageNotFound(String),
+
+    #[error("Dependency cycle between packages: {0}")]
+    DependencyCycle(String),
+
+    #[error("Lockfile already loaded")]
+    LockfileAlreadyLoaded,
+
+    #[error("Failed to parse lockfile {0}: {1}")]
+    LockfileParse(String, toml::de::Error),
+
+    #[error("Failed to serialize to TOML")]
+    TomlSerError(#[from] toml::ser::Error),
+
+    #[error("Failed to (de)serialize JSON")]
+    JsonError(#[from] serde_json::Error),
+
+    #[error("Checksum mismatch for {file}: expected {algorithm} {expected}, got {actual}")]
+    ChecksumMismatch {
+        file: String,
+        algorithm: String,
+        expected: String,
+        actual: String,
+    },
+
+    #[error(
+        "Download of {package} doesn't match the pinned sha256 (expected {expected}, got {actual})"
+    )]
+    LockedChecksumMismatch {
+        package: String,
+        expected: String,
+        actual: String,
+    },
+
+    #[error("Cache dir already set")]
+    CacheDirAlreadySet,
+
+    #[error("Mirrors already set")]
+    MirrorsAlreadySet,
+
+    #[error("{package}: {dir} has local modifications, commit or stash them first")]
+    DirtyCheckout { package: String, dir: String },
+
+    #[error("Unexpected entry {0} in bundle")]
+    BadBundleEntry(String),
+
+    #[error("{path} is outside the cache dir {cache_dir}, it can't be bundled")]
+    OutsideCacheDir { path: String, cache_dir: String },
+
+    #[error("{package}: {url} is not available offline, import a bundle with `xdev bundle import` or drop --offline")]
+    Offline { package: String, url: String },
+
+    #[error("{package}: no patches match {pattern}: {message}")]
+    BadPatchPattern {
+        package: String,
+        pattern: String,
+        message: String,
+    },
+
+    #[error("{package}: failed to apply {patch}: {reason}")]
+    PatchFailed {
+        package: String,
+        patch: String,
+        reason: String,
+    },
+
+    #[error("{package}: the patches applied to {dir} changed, remove the sources with `xdev clean --sources {package}` to start over")]
+    PatchesChanged { package: String, dir: String },
+
+    #[error("Unknown archive format: {0}")]
+    UnknownArchiveFormat(String),
+
+    #[error("Unexpected entry {0} in archive")]
+    BadArchiveEntry(String),
+
+    #[error("Failed to read zip archive")]
+    ZipError(#[from] zip::result::ZipError),
+
+    #[error("{package}: {binary} not found in {place}")]
+    SystemToolNotFound {
+        package: String,
+        binary: String,
+        place: String,
+    },
+
+    #[error("{package}: {path} is version {found}, {required} or newer is required")]
+    SystemToolTooOld {
+        package: String,
+        path: String,
+        found: String,
+        required: String,
+    },
+
+    #[error("Failed to download {url}: {message}")]
+    DownloadFailed { url: String, message: String },
+
+    #[error("`{command}` failed ({status}){}", failure_details(.tail, .log))]
+    CommandFailed {
+        command: String,
+        status: String,
+        tail: Vec<String>,
+        log: Option<String>,
+    },
+}
+
+// The last lines a failed command printed, and where to find the rest
+fn failure_details(tail: &[String], log: &Option<String>) -> String {
+    let mut details = String::new();
+
+    for line in tail {
+        details.push_str(&format!("\n  | {line}"));
+    }
+    if let Some(log) = log {
+        details.push_str(&format!("\nFull log: {log}"));
+    }
+
+    details
 }
 
 pub type Result<T> = std::result::Result<T, CrossDevError>;
@@ -1,4 +1,4 @@
-use crate::{env::*, error::*, utils::*};
+use crate::{env::*, error::*};
 use std::{env, process::Command};
 
 const GDB_DIR: &str = "gdb";
@@ -7,6 +7,7 @@ const GDB_BIN: &str = "gdb-multiarch";
 pub fn run_gdb() -> Result<()> {
     let gdb_dir = format!("{}/{GDB_DIR}", get_dir(EnvType::RootDir)?);
     let work_dir = get_dir(EnvType::WorkDir)?;
+    let build_dir = get_dir(EnvType::BuildDir)?;
 
     let _ = env::set_current_dir(&gdb_dir);
 
@@ -21,6 +22,7 @@ pub fn run_gdb() -> Result<()> {
     let mut child = Command::new(GDB_BIN)
         .args(gdb_args)
         .env("GDB_WORK_DIR", work_dir)
+        .env("GDB_BUILD_DIR", build_dir)
         .spawn()
         .expect("Failed to launch target: {gdb_bin} {gdb_args}");
 
@@ -0,0 +1,453 @@
+use crate::{error::*, reporter::info};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    thread,
+    time::Duration,
+};
+use ureq::{Agent, AgentBuilder};
+
+const DEFAULT_RETRIES: u32 = 3;
+const DEFAULT_BACKOFF: Duration = Duration::from_secs(1);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+const READ_TIMEOUT: Duration = Duration::from_secs(60);
+const CHUNK_SIZE: usize = 64 * 1024;
+// Kept next to the partial file: the ETag or Last-Modified of what it holds
+const VALIDATOR_SUFFIX: &str = ".validator";
+
+/// How far a download got, `total` is unknown when the server doesn't say
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Progress {
+    pub downloaded: u64,
+    pub total: Option<u64>,
+}
+
+type ProgressFn<'a> = Box<dyn Fn(Progress) + Send + Sync + 'a>;
+
+/// Downloads over HTTP(S) into `<dest>.partial`, resuming it with a range
+/// request after a failure as long as the server still has the same version
+/// of the file, and renames it into place once complete.
+/// Proxies come from `http_proxy`, `https_proxy` and `all_proxy`.
+pub struct Downloader<'a> {
+    agent: Agent,
+    retries: u32,
+    backoff: Duration,
+    progress: Option<ProgressFn<'a>>,
+}
+
+// Why an attempt failed, and whether trying again could help
+enum Failure {
+    Retry(String),
+    Fatal(String),
+}
+
+impl Default for Downloader<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> Downloader<'a> {
+    pub fn new() -> Self {
+        let agent = AgentBuilder::new()
+            .try_proxy_from_env(true)
+            .timeout_connect(CONNECT_TIMEOUT)
+            .timeout_read(READ_TIMEOUT)
+            .user_agent(concat!("crossdev/", env!("CARGO_PKG_VERSION")))
+            .build();
+
+        Self {
+            agent,
+            retries: DEFAULT_RETRIES,
+            backoff: DEFAULT_BACKOFF,
+            progress: None,
+        }
+    }
+
+    /// Attempts after the first one before giving up
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Wait before the first retry, doubled for every further one
+    pub fn backoff(mut self, backoff: Duration) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Called after every chunk received
+    pub fn on_progress(mut self, progress: impl Fn(Progress) + Send + Sync + 'a) -> Self {
+        self.progress = Some(Box::new(progress));
+        self
+    }
+
+    pub fn download(&self, url: &str, dest: &str) -> Result<()> {
+        let partial = format!("{dest}.partial");
+        let mut attempt = 0;
+
+        loop {
+            let message = match self.attempt(url, &partial) {
+                Ok(()) => break,
+                Err(Failure::Retry(message)) if attempt < self.retries => {
+                    let delay = self.backoff * 2u32.saturating_pow(attempt);
+                    info(format!("⚠️  {message}, retrying in {delay:?}"));
+                    thread::sleep(delay);
+                    attempt += 1;
+                    continue;
+                }
+                Err(Failure::Retry(message)) | Err(Failure::Fatal(message)) => message,
+            };
+
+            return Err(CrossDevError::DownloadFailed {
+                url: url.to_string(),
+                message,
+            });
+        }
+
+        fs::rename(&partial, dest)?;
+        let _ = fs::remove_file(format!("{partial}{VALIDATOR_SUFFIX}"));
+
+        Ok(())
+    }
+
+    fn attempt(&self, url: &str, partial: &str) -> std::result::Result<(), Failure> {
+        let validator_path = format!("{partial}{VALIDATOR_SUFFIX}");
+        // A partial file of unknown version can't be resumed safely
+        let validator = fs::read_to_string(&validator_path).ok();
+        let offset = match validator {
+            Some(_) => fs::metadata(partial)
+                .map(|metadata| metadata.len())
+                .unwrap_or(0),
+            None => 0,
+        };
+
+        let mut request = self.agent.get(url);
+        if let (true, Some(validator)) = (offset > 0, &validator) {
+            // The server sends the whole file instead if it changed since
+            request = request
+                .set("Range", &format!("bytes={offset}-"))
+                .set("If-Range", validator.trim());
+        }
+
+        let response = match request.call() {
+            Ok(response) => response,
+            // The server has nothing past what we have, start over rather than guess
+            Err(ureq::Error::Status(416, _)) => {
+                let _ = fs::remove_file(partial);
+                let _ = fs::remove_file(&validator_path);
+                return Err(Failure::Retry(
+                    "requested range not satisfiable".to_string(),
+                ));
+            }
+            Err(ureq::Error::Status(status, response)) => {
+                let message = format!("HTTP {status} {}", response.status_text());
+                return Err(match status {
+                    408 | 429 | 500.. => Failure::Retry(message),
+                    _ => Failure::Fatal(message),
+                });
+            }
+            Err(err) => return Err(Failure::Retry(err.to_string())),
+        };
+
+        // A server that ignores the range, or whose file changed, sends
+        // everything again
+        let resumed = response.status() == 206;
+        if !resumed {
+            match validator_of(&response) {
+                Some(validator) => fs::write(&validator_path, validator).map_err(fatal)?,
+                None => {
+                    let _ = fs::remove_file(&validator_path);
+                }
+            }
+        }
+        let (mut file, mut downloaded) = if resumed {
+            (open_append(partial).map_err(fatal)?, offset)
+        } else {
+            (File::create(partial).map_err(fatal)?, 0)
+        };
+        let total = if resumed {
+            response
+                .header("Content-Range")
+                .and_then(|range| range.rsplit('/').next())
+                .and_then(|total| total.parse().ok())
+        } else {
+            response
+                .header("Content-Length")
+                .and_then(|length| length.parse().ok())
+        };
+
+        let mut reader = response.into_reader();
+        let mut buf = vec![0; CHUNK_SIZE];
+        loop {
+            let len = match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(len) => len,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(Failure::Retry(err.to_string())),
+            };
+            file.write_all(&buf[..len]).map_err(fatal)?;
+            downloaded += len as u64;
+
+            if let Some(progress) = &self.progress {
+                progress(Progress { downloaded, total });
+            }
+        }
+
+        match total {
+            Some(total) if downloaded < total => Err(Failure::Retry(format!(
+                "connection closed after {downloaded} of {total} bytes"
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+// What identifies this version of the file for `If-Range`, which only takes
+// a strong ETag
+fn validator_of(response: &ureq::Response) -> Option<String> {
+    response
+        .header("ETag")
+        .filter(|etag| !etag.starts_with("W/"))
+        .or_else(|| response.header("Last-Modified"))
+        .map(str::to_string)
+}
+
+fn open_append(path: &str) -> io::Result<File> {
+    OpenOptions::new().append(true).open(path)
+}
+
+// Local file errors won't go away by downloading again
+fn fatal(err: io::Error) -> Failure {
+    Failure::Fatal(err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        path::PathBuf,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+    };
+    use tiny_http::{Header, Response, Server};
+
+    // What the test server does with the n-th request it gets
+    enum Reply {
+        Body,
+        // The whole body, even when asked for a range
+        Full,
+        Status(u16),
+    }
+
+    struct TestServer {
+        url: String,
+        requests: Arc<AtomicUsize>,
+        ranges: Arc<Mutex<Vec<Option<String>>>>,
+        if_ranges: Arc<Mutex<Vec<Option<String>>>>,
+    }
+
+    // Version of the body the test server has
+    const ETAG: &str = "\"v1\"";
+
+    fn serve(body: &'static [u8], replies: Vec<Reply>) -> TestServer {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/file.tar.gz", server.server_addr());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let ranges = Arc::new(Mutex::new(Vec::new()));
+        let if_ranges = Arc::new(Mutex::new(Vec::new()));
+
+        let (count, seen, seen_if) = (requests.clone(), ranges.clone(), if_ranges.clone());
+        thread::spawn(move || {
+            for request in server.incoming_requests() {
+                let index = count.fetch_add(1, Ordering::SeqCst);
+                let header = |name: &'static str| {
+                    request
+                        .headers()
+                        .iter()
+                        .find(|header| header.field.equiv(name))
+                        .map(|header| header.value.to_string())
+                };
+                let (range, if_range) = (header("Range"), header("If-Range"));
+                seen.lock().unwrap().push(range.clone());
+                seen_if.lock().unwrap().push(if_range.clone());
+
+                // A range of another version of the body is answered with all of this one
+                let range = range.filter(|_| if_range.as_deref() == Some(ETAG));
+                let etag = Header::from_bytes("ETag", ETAG).unwrap();
+
+                let _ = match replies.get(index).unwrap_or(&Reply::Body) {
+                    Reply::Status(status) => request.respond(Response::empty(*status)),
+                    Reply::Full => request.respond(Response::from_data(body)),
+                    Reply::Body => match range_start(range.as_deref()) {
+                        Some(start) => {
+                            let content_range =
+                                format!("bytes {start}-{}/{}", body.len() - 1, body.len());
+                            let response = Response::from_data(&body[start..])
+                                .with_status_code(206)
+                                .with_header(
+                                    Header::from_bytes("Content-Range", content_range).unwrap(),
+                                )
+                                .with_header(etag);
+                            request.respond(response)
+                        }
+                        None => request.respond(Response::from_data(body).with_header(etag)),
+                    },
+                };
+            }
+        });
+
+        TestServer {
+            url,
+            requests,
+            ranges,
+            if_ranges,
+        }
+    }
+
+    fn range_start(range: Option<&str>) -> Option<usize> {
+        range?
+            .strip_prefix("bytes=")?
+            .strip_suffix('-')?
+            .parse()
+            .ok()
+    }
+
+    fn temp_dest(name: &str) -> String {
+        let dir: PathBuf =
+            std::env::temp_dir().join(format!("crossdev-http-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let dest = dir.join(name).display().to_string();
+        let _ = fs::remove_file(&dest);
+        let _ = fs::remove_file(format!("{dest}.partial"));
+        let _ = fs::remove_file(format!("{dest}.partial{VALIDATOR_SUFFIX}"));
+
+        dest
+    }
+
+    // A partial download of the body, as version `etag`
+    fn write_partial(dest: &str, contents: &[u8], etag: Option<&str>) {
+        fs::write(format!("{dest}.partial"), contents).unwrap();
+        if let Some(etag) = etag {
+            fs::write(format!("{dest}.partial{VALIDATOR_SUFFIX}"), etag).unwrap();
+        }
+    }
+
+    fn downloader<'a>() -> Downloader<'a> {
+        Downloader::new().retries(3).backoff(Duration::ZERO)
+    }
+
+    const BODY: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyz";
+
+    #[test]
+    fn downloads_and_renames_into_place() {
+        let server = serve(BODY, vec![]);
+        let dest = temp_dest("full");
+        let progress = Mutex::new(Vec::new());
+
+        downloader()
+            .on_progress(|update| progress.lock().unwrap().push(update))
+            .download(&server.url, &dest)
+            .unwrap();
+
+        assert_eq!(fs::read(&dest).unwrap(), BODY);
+        assert!(!PathBuf::from(format!("{dest}.partial")).exists());
+        assert!(!PathBuf::from(format!("{dest}.partial{VALIDATOR_SUFFIX}")).exists());
+        assert_eq!(
+            progress.lock().unwrap().last(),
+            Some(&Progress {
+                downloaded: BODY.len() as u64,
+                total: Some(BODY.len() as u64),
+            })
+        );
+    }
+
+    #[test]
+    fn resumes_a_partial_download() {
+        let server = serve(BODY, vec![]);
+        let dest = temp_dest("resume");
+        write_partial(&dest, &BODY[..10], Some(ETAG));
+
+        downloader().download(&server.url, &dest).unwrap();
+
+        assert_eq!(fs::read(&dest).unwrap(), BODY);
+        assert_eq!(
+            server.ranges.lock().unwrap().as_slice(),
+            [Some("bytes=10-".to_string())]
+        );
+        assert_eq!(
+            server.if_ranges.lock().unwrap().as_slice(),
+            [Some(ETAG.to_string())]
+        );
+        assert!(!PathBuf::from(format!("{dest}.partial{VALIDATOR_SUFFIX}")).exists());
+    }
+
+    #[test]
+    fn starts_over_when_the_file_changed() {
+        let server = serve(BODY, vec![]);
+        let dest = temp_dest("changed");
+        write_partial(&dest, b"old version", Some("\"v0\""));
+
+        downloader().download(&server.url, &dest).unwrap();
+
+        assert_eq!(fs::read(&dest).unwrap(), BODY);
+        assert_eq!(server.requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn starts_over_without_knowing_the_partial_version() {
+        let server = serve(BODY, vec![]);
+        let dest = temp_dest("unknown");
+        write_partial(&dest, &BODY[..10], None);
+
+        downloader().download(&server.url, &dest).unwrap();
+
+        assert_eq!(fs::read(&dest).unwrap(), BODY);
+        assert_eq!(server.ranges.lock().unwrap().as_slice(), [None]);
+    }
+
+    #[test]
+    fn retries_server_errors() {
+        let server = serve(BODY, vec![Reply::Status(503), Reply::Status(429)]);
+        let dest = temp_dest("retry");
+
+        downloader().download(&server.url, &dest).unwrap();
+
+        assert_eq!(fs::read(&dest).unwrap(), BODY);
+        assert_eq!(server.requests.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn starts_over_when_the_range_is_ignored() {
+        let server = serve(BODY, vec![Reply::Full]);
+        let dest = temp_dest("ignored");
+        write_partial(&dest, b"stale", Some(ETAG));
+
+        downloader().download(&server.url, &dest).unwrap();
+
+        assert_eq!(fs::read(&dest).unwrap(), BODY);
+    }
+
+    #[test]
+    fn gives_up_on_client_errors() {
+        let server = serve(BODY, vec![Reply::Status(404)]);
+        let dest = temp_dest("missing");
+
+        let err = downloader().download(&server.url, &dest).unwrap_err();
+
+        assert!(err.to_string().contains("404"), "{err}");
+        assert_eq!(server.requests.load(Ordering::SeqCst), 1);
+        assert!(!PathBuf::from(&dest).exists());
+    }
+
+    #[test]
+    fn gives_up_after_the_last_retry() {
+        let server = serve(BODY, (0..10).map(|_| Reply::Status(500)).collect());
+        let dest = temp_dest("flaky");
+
+        assert!(downloader().download(&server.url, &dest).is_err());
+        assert_eq!(server.requests.load(Ordering::SeqCst), 4);
+    }
+}
@@ -0,0 +1,296 @@
+use core::fmt;
+use std::{fs, process::Command};
+
+const OS_RELEASE: &str = "/etc/os-release";
+
+/// A `${...}` placeholder in a package field that could not be expanded
+#[derive(Debug, Clone)]
+pub struct InterpolationError {
+    pub package: String,
+    pub field: String,
+    pub message: String,
+}
+
+impl fmt::Display for InterpolationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "package \"{}\": {} in `{}`",
+            self.package, self.message, self.field
+        )
+    }
+}
+
+impl std::error::Error for InterpolationError {}
+
+// Values of the `${host.*}` variables, looked up only when a config uses them
+#[derive(Default)]
+struct HostVars {
+    distro_version: Option<Option<String>>,
+}
+
+impl HostVars {
+    fn distro_version(&mut self) -> Option<String> {
+        self.distro_version
+            .get_or_insert_with(read_distro_version)
+            .clone()
+    }
+}
+
+/// Expands `${version}`, `${arch}`, `${name}` and `${host.distro_version}` in every
+/// string field of every package, returning all placeholders that failed to expand.
+/// `$${` is left as a literal `${`.
+pub fn interpolate_packages(config: &mut toml::Table) -> Vec<InterpolationError> {
+    let arch = config
+        .get("arch")
+        .and_then(|arch| arch.as_str())
+        .map(str::to_string);
+    let Some(packages) = config
+        .get_mut("packages")
+        .and_then(|packages| packages.as_array_mut())
+    else {
+        return Vec::new();
+    };
+
+    let mut host = HostVars::default();
+    let mut errors = Vec::new();
+
+    for package in packages
+        .iter_mut()
+        .filter_map(|package| package.as_table_mut())
+    {
+        let name = package
+            .get("name")
+            .and_then(|name| name.as_str())
+            .unwrap_or("<unnamed>")
+            .to_string();
+        let mut vars = Vars {
+            arch: arch.clone(),
+            name: None,
+            version: None,
+            host: &mut host,
+        };
+
+        // `name` and then `version` are expanded first so the fields after them can
+        // refer to them
+        if let Some(value) = package.get_mut("name") {
+            expand_value(value, "name", &name, &mut vars, &mut errors);
+            vars.name = value.as_str().map(str::to_string);
+        }
+        if let Some(value) = package.get_mut("version") {
+            expand_value(value, "version", &name, &mut vars, &mut errors);
+            vars.version = value.as_str().map(str::to_string);
+        }
+
+        for (key, value) in package.iter_mut() {
+            if key != "name" && key != "version" {
+                expand_value(value, key, &name, &mut vars, &mut errors);
+            }
+        }
+    }
+
+    errors
+}
+
+struct Vars<'a> {
+    arch: Option<String>,
+    name: Option<String>,
+    version: Option<String>,
+    host: &'a mut HostVars,
+}
+
+impl Vars<'_> {
+    fn lookup(&mut self, var: &str) -> Result<String, String> {
+        let value = match var {
+            "arch" => self.arch.clone(),
+            "name" => self.name.clone(),
+            "version" => self.version.clone(),
+            "host.distro_version" => {
+                return self
+                    .host
+                    .distro_version()
+                    .ok_or_else(|| "could not determine `${host.distro_version}`".to_string());
+            }
+            _ => return Err(format!("unknown variable `${{{var}}}`")),
+        };
+
+        value.ok_or_else(|| format!("`${{{var}}}` cannot be used here"))
+    }
+}
+
+fn expand_value(
+    value: &mut toml::Value,
+    field: &str,
+    package: &str,
+    vars: &mut Vars,
+    errors: &mut Vec<InterpolationError>,
+) {
+    match value {
+        toml::Value::String(string) => match expand(string, vars) {
+            Ok(expanded) => *string = expanded,
+            Err(message) => errors.push(InterpolationError {
+                package: package.to_string(),
+                field: field.to_string(),
+                message,
+            }),
+        },
+        toml::Value::Array(array) => {
+            for (index, value) in array.iter_mut().enumerate() {
+                expand_value(value, &format!("{field}[{index}]"), package, vars, errors);
+            }
+        }
+        toml::Value::Table(table) => {
+            for (key, value) in table.iter_mut() {
+                expand_value(value, &format!("{field}.{key}"), package, vars, errors);
+            }
+        }
+        _ => {}
+    }
+}
+
+// `$${` stands for a literal `${`, e.g. for shell variables in script steps
+fn expand(input: &str, vars: &mut Vars) -> Result<String, String> {
+    let mut output = String::with_capacity(input.len());
+    let mut rest = input;
+
+    while let Some(start) = rest.find('$') {
+        output.push_str(&rest[..start]);
+        let from = &rest[start..];
+
+        if let Some(escaped) = from.strip_prefix("$${") {
+            output.push_str("${");
+            rest = escaped;
+            continue;
+        }
+        let Some(after) = from.strip_prefix("${") else {
+            output.push('$');
+            rest = &from[1..];
+            continue;
+        };
+
+        let end = after
+            .find('}')
+            .ok_or_else(|| format!("unterminated `${{` in \"{input}\""))?;
+        output.push_str(&vars.lookup(after[..end].trim())?);
+        rest = &after[end + 1..];
+    }
+    output.push_str(rest);
+
+    Ok(output)
+}
+
+fn read_distro_version() -> Option<String> {
+    let from_os_release = fs::read_to_string(OS_RELEASE).ok().and_then(|content| {
+        content.lines().find_map(|line| {
+            line.strip_prefix("VERSION_ID=")
+                .map(|version| version.trim_matches('"').to_string())
+        })
+    });
+
+    from_os_release.or_else(|| {
+        let output = Command::new("lsb_release").arg("-rs").output().ok()?;
+        let version = String::from_utf8(output.stdout).ok()?.trim().to_string();
+        (!version.is_empty()).then_some(version)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(host: &mut HostVars) -> Vars<'_> {
+        Vars {
+            arch: Some("riscv64".to_string()),
+            name: Some("qemu".to_string()),
+            version: Some("9.2.0".to_string()),
+            host,
+        }
+    }
+
+    fn expanded(input: &str) -> Result<String, String> {
+        let mut host = HostVars {
+            distro_version: Some(Some("24.04".to_string())),
+        };
+        expand(input, &mut vars(&mut host))
+    }
+
+    #[test]
+    fn expands_every_variable() {
+        assert_eq!(
+            expanded("${name}-${ version }-${arch}-${host.distro_version}").unwrap(),
+            "qemu-9.2.0-riscv64-24.04"
+        );
+    }
+
+    #[test]
+    fn leaves_plain_dollars_and_escapes_alone() {
+        assert_eq!(expanded("echo $HOME $").unwrap(), "echo $HOME $");
+        assert_eq!(
+            expanded("echo $${SOURCE_DIR}/${version}").unwrap(),
+            "echo ${SOURCE_DIR}/9.2.0"
+        );
+    }
+
+    #[test]
+    fn reports_unknown_and_unterminated_placeholders() {
+        assert_eq!(
+            expanded("${SOURCE_DIR}").unwrap_err(),
+            "unknown variable `${SOURCE_DIR}`"
+        );
+        assert!(expanded("qemu-${version")
+            .unwrap_err()
+            .starts_with("unterminated"));
+    }
+
+    #[test]
+    fn reports_an_unknown_host_distro() {
+        let mut host = HostVars {
+            distro_version: Some(None),
+        };
+
+        assert!(expand("${host.distro_version}", &mut vars(&mut host)).is_err());
+    }
+
+    #[test]
+    fn expands_packages_after_their_name_and_version() {
+        let mut config: toml::Table = toml::from_str(
+            r#"
+arch = "riscv64"
+
+[[packages]]
+name = "linux"
+version = "v${arch}"
+download = { url = "https://example.com/${name}-${version}.tar.xz" }
+script = { build = ["echo $${JOBS}"] }
+"#,
+        )
+        .unwrap();
+
+        assert!(interpolate_packages(&mut config).is_empty());
+        let package = &config["packages"][0];
+        assert_eq!(
+            package["download"]["url"].as_str(),
+            Some("https://example.com/linux-vriscv64.tar.xz")
+        );
+        assert_eq!(package["script"]["build"][0].as_str(), Some("echo ${JOBS}"));
+    }
+
+    #[test]
+    fn expands_a_version_that_refers_to_the_name() {
+        let mut config: toml::Table = toml::from_str(
+            r#"
+[[packages]]
+name = "hello"
+version = "${name}-1.0"
+download = { url = "https://example.com/${version}.tar.gz" }
+"#,
+        )
+        .unwrap();
+
+        assert!(interpolate_packages(&mut config).is_empty());
+        assert_eq!(
+            config["packages"][0]["download"]["url"].as_str(),
+            Some("https://example.com/hello-1.0.tar.gz")
+        );
+    }
+}
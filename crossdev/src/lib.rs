@@ -1,13 +1,29 @@
+pub mod archive;
 mod buildroot;
+pub mod bundle;
+pub mod cache;
 pub mod config;
 pub mod crossdev;
+mod custom;
+mod download;
 pub mod env;
 pub mod error;
 pub mod gdb;
+pub mod http;
+pub mod interpolate;
 mod linux;
+pub mod lock;
+pub mod mirror;
 mod opensbi;
+mod patch;
 mod qemu;
+pub mod reporter;
+pub mod runner;
 pub mod ssh;
+pub mod stamp;
+pub mod status;
+mod system;
 mod toolchain;
 mod traits;
 pub mod utils;
+pub mod validate;
@@ -1,10 +1,25 @@
-use crate::{config::*, env::*, error::Result, traits::Installable, utils::*};
-use std::fs;
+use crate::{
+    config::*,
+    download::*,
+    env::*,
+    error::Result,
+    patch::apply_patches,
+    runner::{copy_file, make_dir, RunLogged},
+    stamp::*,
+    traits::Installable,
+    utils::*,
+};
 use xshell::{cmd, Shell};
 
+const BUILD_TYPE_FRAGMENT: &str = "build_type.config";
+const DEFAULT_DEFCONFIG: &str = "defconfig";
+
 #[derive(Debug)]
 pub struct Linux {
     arch: String,
+    build_type: BuildType,
+    sanitizers: Vec<Sanitizer>,
+    flavor: ToolchainFlavor,
     package: Package,
     build_dir: String,
     linux_dir: String,
@@ -13,15 +28,24 @@ pub struct Linux {
 }
 
 impl Linux {
-    pub fn new(arch: String, package: Package) -> Result<Self> {
-        let linux_dir_name = format!("linux");
+    pub fn new(
+        arch: String,
+        build_type: BuildType,
+        sanitizers: Vec<Sanitizer>,
+        flavor: ToolchainFlavor,
+        package: Package,
+    ) -> Result<Self> {
+        let linux_dir_name = "linux";
         let build_dir = format!("{}/{linux_dir_name}", get_dir(EnvType::BuildDir)?);
-        let linux_dir = format!("{}/{linux_dir_name}", get_dir(EnvType::DownloadDir)?);
-        let bin_name = format!("Image");
+        let linux_dir = source_dir(&package.download, linux_dir_name)?;
+        let bin_name = "Image".to_string();
         let bin_path = format!("{build_dir}/arch/riscv/boot/{bin_name}");
 
         Ok(Self {
             arch,
+            build_type,
+            sanitizers,
+            flavor,
             package,
             build_dir,
             linux_dir,
@@ -29,6 +53,24 @@ impl Linux {
             bin_path,
         })
     }
+
+    // Kconfig symbols forced on or off depending on the build type
+    fn build_type_symbols(&self) -> Vec<(&'static str, bool)> {
+        let debug = self.build_type == BuildType::Debug;
+        let sanitizer = |sanitizer| debug && self.sanitizers.contains(&sanitizer);
+
+        vec![
+            ("CONFIG_DEBUG_INFO_NONE", !debug),
+            ("CONFIG_DEBUG_INFO_DWARF_TOOLCHAIN_DEFAULT", debug),
+            ("CONFIG_DEBUG_INFO", debug),
+            ("CONFIG_FRAME_POINTER", debug),
+            ("CONFIG_GDB_SCRIPTS", debug),
+            ("CONFIG_KASAN", sanitizer(Sanitizer::Kasan)),
+            ("CONFIG_KASAN_GENERIC", sanitizer(Sanitizer::Kasan)),
+            ("CONFIG_KCSAN", sanitizer(Sanitizer::Kcsan)),
+            ("CONFIG_UBSAN", sanitizer(Sanitizer::Ubsan)),
+        ]
+    }
 }
 
 impl Installable for Linux {
@@ -36,50 +78,80 @@ impl Installable for Linux {
         &self.package.name
     }
 
-    fn download(&self) -> Result<()> {
-        let sh = Shell::new()?;
+    fn pack_type(&self) -> PackType {
+        self.package.pack_type
+    }
 
+    fn download(&self) -> Result<()> {
         match &self.package.download {
-            Download::Git(git) => {
-                let repo = git.url.as_str();
-                let branch = git.branch.as_str();
-                let linux_dir = self.linux_dir.as_str();
-
-                println!("📦 Cloning {repo}...");
-
-                if !sh.path_exists(linux_dir) {
-                    cmd!(
-                        sh,
-                        "git clone --depth 1 -b {branch} --single-branch {repo} {linux_dir}"
-                    )
-                    .run_echo()?;
-                }
-            }
+            Download::Git(git) => git_clone(self.name(), git, &self.linux_dir)?,
+            Download::Path(local) => check_local_source(local)?,
             _ => {}
         }
 
         Ok(())
     }
 
+    fn patch(&self) -> Result<()> {
+        apply_patches(&self.package, &self.linux_dir)
+    }
+
+    fn update(&self) -> Result<()> {
+        if let Download::Git(git) = &self.package.download {
+            git_update(self.name(), git, &self.linux_dir)?;
+        }
+
+        Ok(())
+    }
+
     fn build(&self) -> Result<()> {
         let mut sh = Shell::new()?;
 
         let cross_toolchain = get_cross_toolchain_path()?;
         let linux_dir = self.linux_dir.as_str();
         let linux_build_dir = self.build_dir.as_str();
-        sh.create_dir(linux_build_dir)?;
+        make_dir(linux_build_dir)?;
         sh.set_current_dir(linux_build_dir);
 
-        let envs = [("ARCH", "riscv"), ("CROSS_COMPILE", &cross_toolchain)];
-
-        for (k, v) in envs {
-            sh.set_var(k, v)
+        sh.set_var("ARCH", kernel_arch(&self.arch));
+        // With LLVM=1 clang picks its target from ARCH, CROSS_COMPILE would override it
+        match self.flavor {
+            ToolchainFlavor::Gnu => sh.set_var("CROSS_COMPILE", cross_toolchain),
+            ToolchainFlavor::Llvm => sh.set_var("PATH", llvm_search_path()?),
         }
 
-        cmd!(sh, "make O={linux_build_dir} -C {linux_dir} defconfig").run_echo()?;
-
-        let nproc = cmd!(sh, "nproc").read()?;
-        cmd!(sh, "make -j{nproc}").run_echo()?;
+        let build = &self.package.build;
+        build.apply_env(&mut sh);
+        let mut make_vars = build.make_vars();
+        if self.flavor == ToolchainFlavor::Llvm && !build.make_vars.contains_key("LLVM") {
+            make_vars.insert(0, "LLVM=1".to_string());
+        }
+        let make_vars = &make_vars;
+        let targets = &build.targets;
+        let defconfig = build.defconfig.as_deref().unwrap_or(DEFAULT_DEFCONFIG);
+
+        cmd!(
+            sh,
+            "make O={linux_build_dir} -C {linux_dir} {make_vars...} {defconfig}"
+        )
+        .run_logged()?;
+
+        // Apply the build type on top of defconfig
+        let fragment = format!("{linux_build_dir}/{BUILD_TYPE_FRAGMENT}");
+        write_kconfig_fragment(&fragment, &self.build_type_symbols())?;
+        cmd!(
+            sh,
+            "{linux_dir}/scripts/kconfig/merge_config.sh -m -O {linux_build_dir} {linux_build_dir}/.config {fragment}"
+        )
+        .run_logged()?;
+        cmd!(
+            sh,
+            "make O={linux_build_dir} -C {linux_dir} {make_vars...} olddefconfig"
+        )
+        .run_logged()?;
+
+        let nproc = get_jobs()?;
+        cmd!(sh, "make -j{nproc} {make_vars...} {targets...}").run_logged()?;
 
         Ok(())
     }
@@ -89,13 +161,64 @@ impl Installable for Linux {
         let bin_path = self.bin_path.as_str();
         let image_path = format!("{}/{}", get_dir(EnvType::ImageDir)?, self.bin_name);
 
-        println!("📦 Copying {bin_path} to {image_path}...");
+        copy_file(bin_path, &image_path)
+    }
 
-        fs::copy(bin_path, image_path)?;
+    fn clean(&self) -> Result<()> {
+        let mut sh = Shell::new()?;
+        let linux_dir = self.linux_dir.as_str();
+        let linux_build_dir = self.build_dir.as_str();
+
+        if sh.path_exists(linux_build_dir) && sh.path_exists(linux_dir) {
+            sh.set_var("ARCH", kernel_arch(&self.arch));
+            cmd!(sh, "make O={linux_build_dir} -C {linux_dir} clean").run_logged()?;
+        }
 
         Ok(())
     }
 
+    fn distclean(&self) -> Result<()> {
+        remove_dir(&self.build_dir)?;
+        remove_sources(&self.package.download, &self.linux_dir)
+    }
+
+    fn fingerprint(&self, phase: Phase) -> Result<Option<Fingerprint>> {
+        let download = &self.package.download;
+
+        match phase {
+            Phase::Download => download_fingerprint(&self.package, &self.linux_dir),
+            Phase::Build => {
+                let Some(revision) = source_revision(download, &self.linux_dir)? else {
+                    return Ok(None);
+                };
+
+                Ok(Some(
+                    Fingerprint::new()
+                        .add("package", format!("{:?}", self.package))
+                        .add("revision", revision)
+                        .add("arch", &self.arch)
+                        .add("toolchain", get_cross_toolchain_path()?)
+                        .add("flavor", self.flavor)
+                        .add("fragment", format!("{:?}", self.build_type_symbols()))
+                        .add("output", file_signature(&self.bin_path)),
+                ))
+            }
+            Phase::Install => {
+                let image_path = format!("{}/{}", get_dir(EnvType::ImageDir)?, self.bin_name);
+                Ok(Some(install_fingerprint(&self.bin_path, &image_path)))
+            }
+        }
+    }
+
+    fn image_path(&self) -> Result<Option<String>> {
+        let image_dir = get_dir(EnvType::ImageDir)?;
+        Ok(Some(format!("{image_dir}/{}", self.bin_name)))
+    }
+
+    fn source_dir(&self) -> &str {
+        &self.linux_dir
+    }
+
     fn build_dir(&self) -> &str {
         &self.build_dir
     }
@@ -104,7 +227,7 @@ impl Installable for Linux {
         &self.bin_name
     }
 
-    fn bin_path(&self) -> &str {
-        &self.bin_path
+    fn bin_path(&self) -> Result<&str> {
+        Ok(&self.bin_path)
     }
 }
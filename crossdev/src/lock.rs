@@ -0,0 +1,129 @@
+use crate::{error::*, runner::is_dry_run};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+};
+
+pub const LOCKFILE_NAME: &str = "xdev.lock";
+
+const LOCKFILE_HEADER: &str =
+    "# Generated by xdev. Run `xdev update <package>` to move a package to its latest source.\n\n";
+
+/// Resolved sources for every package, so setups can be reproduced later
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Lockfile {
+    #[serde(default)]
+    pub packages: BTreeMap<String, LockedSource>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LockedSource {
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub commit: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+}
+
+struct LoadedLockfile {
+    path: PathBuf,
+    lockfile: Lockfile,
+}
+
+static LOCKFILE: OnceLock<Mutex<LoadedLockfile>> = OnceLock::new();
+
+/// The lockfile lives next to the config file it was resolved from
+pub fn lockfile_path<P>(config_path: P) -> PathBuf
+where
+    P: AsRef<Path>,
+{
+    config_path
+        .as_ref()
+        .parent()
+        .unwrap_or(Path::new("."))
+        .join(LOCKFILE_NAME)
+}
+
+/// Loads (or starts) the lockfile; without it sources are resolved but never pinned
+pub fn load_lockfile<P>(path: P) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    let path = path.as_ref().to_path_buf();
+    let lockfile = match fs::read_to_string(&path) {
+        Ok(content) => toml::from_str(&content)
+            .map_err(|err| CrossDevError::LockfileParse(path.display().to_string(), err))?,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Lockfile::default(),
+        Err(err) => return Err(err.into()),
+    };
+
+    LOCKFILE
+        .set(Mutex::new(LoadedLockfile { path, lockfile }))
+        .map_err(|_| CrossDevError::LockfileAlreadyLoaded)?;
+
+    Ok(())
+}
+
+/// Path of the loaded lockfile
+pub fn lockfile_location() -> Option<PathBuf> {
+    let loaded = LOCKFILE.get()?.lock().unwrap();
+
+    Some(loaded.path.clone())
+}
+
+/// Returns the pin for `name`, unless it was resolved from a different URL
+pub fn locked_source(name: &str, url: &str) -> Option<LockedSource> {
+    let loaded = LOCKFILE.get()?.lock().unwrap();
+
+    loaded
+        .lockfile
+        .packages
+        .get(name)
+        .filter(|source| source.url == url)
+        .cloned()
+}
+
+/// Returns whatever `name` is pinned to, regardless of its URL
+pub fn locked_package(name: &str) -> Option<LockedSource> {
+    let loaded = LOCKFILE.get()?.lock().unwrap();
+
+    loaded.lockfile.packages.get(name).cloned()
+}
+
+/// Pins `name` to `source`; dry runs never touch the lockfile
+pub fn lock_source(name: &str, source: LockedSource) -> Result<()> {
+    let Some(loaded) = LOCKFILE.get().filter(|_| !is_dry_run()) else {
+        return Ok(());
+    };
+    let mut loaded = loaded.lock().unwrap();
+
+    if loaded.lockfile.packages.get(name) != Some(&source) {
+        loaded.lockfile.packages.insert(name.to_string(), source);
+        write_lockfile(&loaded)?;
+    }
+
+    Ok(())
+}
+
+pub fn unlock_source(name: &str) -> Result<()> {
+    let Some(loaded) = LOCKFILE.get().filter(|_| !is_dry_run()) else {
+        return Ok(());
+    };
+    let mut loaded = loaded.lock().unwrap();
+
+    if loaded.lockfile.packages.remove(name).is_some() {
+        write_lockfile(&loaded)?;
+    }
+
+    Ok(())
+}
+
+fn write_lockfile(loaded: &LoadedLockfile) -> Result<()> {
+    let content = toml::to_string(&loaded.lockfile)?;
+    fs::write(&loaded.path, format!("{LOCKFILE_HEADER}{content}"))?;
+
+    Ok(())
+}
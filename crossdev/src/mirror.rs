@@ -0,0 +1,154 @@
+use crate::{error::*, reporter::info};
+use std::{collections::BTreeMap, sync::OnceLock};
+
+// URL prefix rewrites from the config's `[mirrors]` table
+static MIRRORS: OnceLock<BTreeMap<String, String>> = OnceLock::new();
+
+pub fn set_mirrors(mirrors: BTreeMap<String, String>) -> Result<()> {
+    MIRRORS
+        .set(mirrors)
+        .map_err(|_| CrossDevError::MirrorsAlreadySet)?;
+    Ok(())
+}
+
+/// `url` with the longest matching prefix of the mirror table replaced
+pub fn rewrite(url: &str) -> Option<String> {
+    MIRRORS
+        .get()?
+        .iter()
+        .filter(|(from, _)| url.starts_with(from.as_str()))
+        .max_by_key(|(from, _)| from.len())
+        .map(|(from, to)| format!("{to}{}", &url[from.len()..]))
+}
+
+/// Every URL `url` can be fetched from, in the order to try them: each of
+/// `url` and its package `mirrors`, preceded by its rewrite if there is one
+pub fn candidate_urls(url: &str, mirrors: &[String]) -> Vec<String> {
+    let mut candidates = Vec::new();
+
+    for url in std::iter::once(url).chain(mirrors.iter().map(String::as_str)) {
+        for candidate in rewrite(url).into_iter().chain([url.to_string()]) {
+            if !candidates.contains(&candidate) {
+                candidates.push(candidate);
+            }
+        }
+    }
+
+    candidates
+}
+
+/// Runs `fetch` with each candidate URL until one succeeds, and returns the
+/// last error if none does
+pub fn try_mirrors<T>(
+    url: &str,
+    mirrors: &[String],
+    fetch: impl Fn(&str) -> Result<T>,
+) -> Result<T> {
+    let candidates = candidate_urls(url, mirrors);
+    let mut candidates = candidates.iter().peekable();
+
+    loop {
+        let candidate = candidates.next().expect("`url` is always a candidate");
+        match fetch(candidate) {
+            Ok(value) => return Ok(value),
+            Err(err) => match candidates.peek() {
+                Some(next) => info(format!("⚠️  {err}, trying {next}")),
+                None => return Err(err),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    // The table is global, so every test shares this one
+    fn mirrors() {
+        MIRRORS.get_or_init(|| {
+            BTreeMap::from([
+                (
+                    "https://github.com/".to_string(),
+                    "https://mirror.example.com/github/".to_string(),
+                ),
+                (
+                    "https://github.com/torvalds/".to_string(),
+                    "file:///srv/git/".to_string(),
+                ),
+            ])
+        });
+    }
+
+    #[test]
+    fn rewrites_the_longest_matching_prefix() {
+        mirrors();
+
+        assert_eq!(
+            rewrite("https://github.com/torvalds/linux.git").as_deref(),
+            Some("file:///srv/git/linux.git")
+        );
+        assert_eq!(
+            rewrite("https://github.com/riscv-software-src/opensbi.git").as_deref(),
+            Some("https://mirror.example.com/github/riscv-software-src/opensbi.git")
+        );
+        assert_eq!(rewrite("https://download.qemu.org/qemu-9.2.0.tar.xz"), None);
+    }
+
+    #[test]
+    fn lists_each_rewrite_before_its_url() {
+        mirrors();
+        let mirrors = [
+            "https://gitlab.com/qemu-project/qemu.git".to_string(),
+            "https://github.com/qemu/qemu.git".to_string(),
+        ];
+
+        assert_eq!(
+            candidate_urls("https://github.com/qemu/qemu.git", &mirrors),
+            [
+                "https://mirror.example.com/github/qemu/qemu.git",
+                "https://github.com/qemu/qemu.git",
+                "https://gitlab.com/qemu-project/qemu.git",
+            ]
+        );
+    }
+
+    #[test]
+    fn tries_candidates_until_one_succeeds() {
+        mirrors();
+        let url = "https://example.org/a.tar.gz";
+        let mirrors = ["https://one.example.org/a.tar.gz".to_string()];
+        let tried = RefCell::new(Vec::new());
+
+        let fetched = try_mirrors(url, &mirrors, |url| {
+            tried.borrow_mut().push(url.to_string());
+            if url.contains("one.") {
+                Ok(url.to_string())
+            } else {
+                Err(CrossDevError::DownloadFailed {
+                    url: url.to_string(),
+                    message: "HTTP 404".to_string(),
+                })
+            }
+        });
+
+        assert_eq!(fetched.unwrap(), mirrors[0]);
+        assert_eq!(tried.into_inner(), [url, mirrors[0].as_str()]);
+    }
+
+    #[test]
+    fn returns_the_last_error_when_every_candidate_fails() {
+        mirrors();
+        let mirrors = ["https://two.example.org/b.tar.gz".to_string()];
+
+        let err = try_mirrors("https://example.org/b.tar.gz", &mirrors, |url| {
+            Err::<(), _>(CrossDevError::DownloadFailed {
+                url: url.to_string(),
+                message: "HTTP 503".to_string(),
+            })
+        })
+        .unwrap_err();
+
+        assert!(err.to_string().contains("two.example.org"), "{err}");
+    }
+}
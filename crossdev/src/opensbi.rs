@@ -1,10 +1,24 @@
-use crate::{config::*, env::*, error::Result, traits::Installable, utils::*};
-use std::fs;
+use crate::{
+    config::*,
+    download::*,
+    env::*,
+    error::Result,
+    patch::apply_patches,
+    runner::{copy_file, make_dir, RunLogged},
+    stamp::*,
+    traits::Installable,
+    utils::*,
+};
 use xshell::{cmd, Shell};
 
+const DEFAULT_PLATFORM: &str = "generic";
+// What the Linux package installs into the image directory
+const LINUX_IMAGE: &str = "Image";
+
 #[derive(Debug)]
 pub struct Opensbi {
-    arch: String,
+    build_type: BuildType,
+    flavor: ToolchainFlavor,
     package: Package,
     build_dir: String,
     opensbi_dir: String,
@@ -13,15 +27,26 @@ pub struct Opensbi {
 }
 
 impl Opensbi {
-    pub fn new(arch: String, package: Package) -> Result<Self> {
-        let opensbi_dir_name = format!("opensbi");
+    pub fn new(
+        _arch: String,
+        build_type: BuildType,
+        flavor: ToolchainFlavor,
+        package: Package,
+    ) -> Result<Self> {
+        let opensbi_dir_name = "opensbi";
         let build_dir = format!("{}/{opensbi_dir_name}", get_dir(EnvType::BuildDir)?);
-        let opensbi_dir = format!("{}/{opensbi_dir_name}", get_dir(EnvType::DownloadDir)?);
-        let bin_name = format!("fw_jump.bin");
-        let bin_path = format!("{build_dir}/platform/generic/firmware/{bin_name}");
+        let opensbi_dir = source_dir(&package.download, opensbi_dir_name)?;
+        let bin_name = "fw_jump.bin".to_string();
+        let platform = package
+            .build
+            .make_vars
+            .get("PLATFORM")
+            .map_or(DEFAULT_PLATFORM, |platform| platform.as_str());
+        let bin_path = format!("{build_dir}/platform/{platform}/firmware/{bin_name}");
 
         Ok(Self {
-            arch,
+            build_type,
+            flavor,
             package,
             build_dir,
             opensbi_dir,
@@ -29,6 +54,20 @@ impl Opensbi {
             bin_path,
         })
     }
+
+    // The image a fw_payload firmware embeds: FW_PAYLOAD_PATH, or the
+    // installed kernel image when only FW_PAYLOAD is set
+    fn payload_path(&self) -> Result<Option<String>> {
+        let make_vars = &self.package.build.make_vars;
+
+        Ok(match make_vars.get("FW_PAYLOAD_PATH") {
+            Some(path) => Some(path.clone()),
+            None if make_vars.contains_key("FW_PAYLOAD") => {
+                Some(format!("{}/{LINUX_IMAGE}", get_dir(EnvType::ImageDir)?))
+            }
+            None => None,
+        })
+    }
 }
 
 impl Installable for Opensbi {
@@ -36,51 +75,70 @@ impl Installable for Opensbi {
         &self.package.name
     }
 
-    fn download(&self) -> Result<()> {
-        let sh = Shell::new()?;
+    fn pack_type(&self) -> PackType {
+        self.package.pack_type
+    }
 
+    fn download(&self) -> Result<()> {
         match &self.package.download {
-            Download::Git(git) => {
-                let repo = git.url.as_str();
-                let branch = git.branch.as_str();
-                let opensbi_dir = self.opensbi_dir.as_str();
-
-                println!("📦 Cloning {repo}...");
-
-                if !sh.path_exists(opensbi_dir) {
-                    cmd!(
-                        sh,
-                        "git clone --depth 1 -b {branch} --single-branch {repo} {opensbi_dir}"
-                    )
-                    .run_echo()?;
-                }
-            }
+            Download::Git(git) => git_clone(self.name(), git, &self.opensbi_dir)?,
+            Download::Path(local) => check_local_source(local)?,
             _ => {}
         }
 
         Ok(())
     }
 
+    fn patch(&self) -> Result<()> {
+        apply_patches(&self.package, &self.opensbi_dir)
+    }
+
+    fn update(&self) -> Result<()> {
+        if let Download::Git(git) = &self.package.download {
+            git_update(self.name(), git, &self.opensbi_dir)?;
+        }
+
+        Ok(())
+    }
+
     fn build(&self) -> Result<()> {
         let mut sh = Shell::new()?;
 
         let cross_toolchain = get_cross_toolchain_path()?;
         let opensbi_dir = self.opensbi_dir.as_str();
         let opensbi_build_dir = self.build_dir.as_str();
-        sh.create_dir(opensbi_build_dir)?;
+        make_dir(opensbi_build_dir)?;
         sh.set_current_dir(opensbi_dir);
 
-        let envs = [("CROSS_COMPILE", &cross_toolchain)];
+        // LLVM=y builds call clang and its tools by name and target them from PLATFORM
+        match self.flavor {
+            ToolchainFlavor::Gnu => sh.set_var("CROSS_COMPILE", cross_toolchain),
+            ToolchainFlavor::Llvm => sh.set_var("PATH", llvm_search_path()?),
+        }
+
+        let build = &self.package.build;
+        build.apply_env(&mut sh);
+        let targets = &build.targets;
 
-        for (k, v) in envs {
-            sh.set_var(k, v)
+        // PLATFORM may be overridden from the config like any other make variable
+        let mut make_vars = build.make_vars();
+        if !build.make_vars.contains_key("PLATFORM") {
+            make_vars.insert(0, format!("PLATFORM={DEFAULT_PLATFORM}"));
         }
+        if self.flavor == ToolchainFlavor::Llvm && !build.make_vars.contains_key("LLVM") {
+            make_vars.insert(0, "LLVM=y".to_string());
+        }
+
+        let debug_vars = match self.build_type {
+            BuildType::Debug => vec!["DEBUG=1"],
+            BuildType::Release => vec![],
+        };
 
         cmd!(
             sh,
-            "make PLATFORM=generic O={opensbi_build_dir} -C {opensbi_dir}"
+            "make O={opensbi_build_dir} -C {opensbi_dir} {make_vars...} {debug_vars...} {targets...}"
         )
-        .run_echo()?;
+        .run_logged()?;
 
         Ok(())
     }
@@ -90,12 +148,76 @@ impl Installable for Opensbi {
         let bin_path = self.bin_path.as_str();
         let image_path = format!("{}/{}", get_dir(EnvType::ImageDir)?, self.bin_name);
 
-        println!("📦 Copying {bin_path} to {image_path}...");
-        fs::copy(bin_path, image_path)?;
+        copy_file(bin_path, &image_path)
+    }
+
+    fn dependencies(&self) -> Vec<PackType> {
+        // A fw_payload firmware embeds the kernel image
+        let make_vars = &self.package.build.make_vars;
+        if make_vars.contains_key("FW_PAYLOAD_PATH") || make_vars.contains_key("FW_PAYLOAD") {
+            vec![PackType::Linux]
+        } else {
+            Vec::new()
+        }
+    }
+
+    fn clean(&self) -> Result<()> {
+        let sh = Shell::new()?;
+        let opensbi_dir = self.opensbi_dir.as_str();
+        let opensbi_build_dir = self.build_dir.as_str();
+
+        if sh.path_exists(opensbi_build_dir) && sh.path_exists(opensbi_dir) {
+            cmd!(sh, "make O={opensbi_build_dir} -C {opensbi_dir} clean").run_logged()?;
+        }
 
         Ok(())
     }
 
+    fn distclean(&self) -> Result<()> {
+        remove_dir(&self.build_dir)?;
+        remove_sources(&self.package.download, &self.opensbi_dir)
+    }
+
+    fn fingerprint(&self, phase: Phase) -> Result<Option<Fingerprint>> {
+        let download = &self.package.download;
+
+        match phase {
+            Phase::Download => download_fingerprint(&self.package, &self.opensbi_dir),
+            Phase::Build => {
+                let Some(revision) = source_revision(download, &self.opensbi_dir)? else {
+                    return Ok(None);
+                };
+
+                let mut fingerprint = Fingerprint::new()
+                    .add("package", format!("{:?}", self.package))
+                    .add("revision", revision)
+                    .add("build_type", self.build_type)
+                    .add("toolchain", get_cross_toolchain_path()?)
+                    .add("flavor", self.flavor)
+                    .add("output", file_signature(&self.bin_path));
+                // A fw_payload firmware is stale as soon as the kernel it embeds changes
+                if let Some(payload) = self.payload_path()? {
+                    fingerprint = fingerprint.add("payload", file_signature(&payload));
+                }
+
+                Ok(Some(fingerprint))
+            }
+            Phase::Install => {
+                let image_path = format!("{}/{}", get_dir(EnvType::ImageDir)?, self.bin_name);
+                Ok(Some(install_fingerprint(&self.bin_path, &image_path)))
+            }
+        }
+    }
+
+    fn image_path(&self) -> Result<Option<String>> {
+        let image_dir = get_dir(EnvType::ImageDir)?;
+        Ok(Some(format!("{image_dir}/{}", self.bin_name)))
+    }
+
+    fn source_dir(&self) -> &str {
+        &self.opensbi_dir
+    }
+
     fn build_dir(&self) -> &str {
         &self.build_dir
     }
@@ -104,7 +226,7 @@ impl Installable for Opensbi {
         &self.bin_name
     }
 
-    fn bin_path(&self) -> &str {
-        &self.bin_path
+    fn bin_path(&self) -> Result<&str> {
+        Ok(&self.bin_path)
     }
 }
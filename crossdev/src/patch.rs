@@ -0,0 +1,394 @@
+use crate::{
+    config::*,
+    download::{git_head, sha256_file},
+    env::*,
+    error::*,
+    reporter::info,
+    runner::{is_dry_run, RunLogged},
+    stamp::Fingerprint,
+    utils::expand_home,
+};
+use serde::{Deserialize, Serialize};
+use std::{fs, path::Path};
+use xshell::{cmd, Shell};
+
+// Kept inside `.git` for checkouts so it doesn't show up as a local change
+const GIT_STATE_FILE: &str = ".git/xdev-patches.json";
+const STATE_FILE: &str = ".xdev-patches.json";
+
+/// Patches applied to a source tree so far, in order
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct PatchState {
+    // Commit the patches were applied on top of, and the one they ended at
+    base: Option<String>,
+    head: Option<String>,
+    applied: Vec<AppliedPatch>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct AppliedPatch {
+    name: String,
+    sha256: String,
+}
+
+/// Applies the package's `patches` to its sources in `dir`, in order, with
+/// `git am` for checkouts and `patch` otherwise. Patches applied by an earlier
+/// setup are skipped.
+pub fn apply_patches(package: &Package, dir: &str) -> Result<()> {
+    if package.patches.is_empty() {
+        return Ok(());
+    }
+
+    let name = package.name.as_str();
+    match &package.download {
+        Download::Path(_) => {
+            info(format!("📂 Not patching local sources at {dir}"));
+            return Ok(());
+        }
+        Download::Apt(_) => return Ok(()),
+        _ => {}
+    }
+
+    let patches = find_patches(package)?;
+    let git = matches!(package.download, Download::Git(_));
+
+    // Nothing was downloaded in a dry run, so every patch is planned
+    if is_dry_run() {
+        for patch in &patches {
+            apply(name, dir, git, patch)?;
+        }
+        return Ok(());
+    }
+
+    let mut state = read_state(dir, git)?;
+    let wanted = patches
+        .iter()
+        .map(|patch| {
+            Ok(AppliedPatch {
+                name: patch.clone(),
+                sha256: sha256_file(patch)?,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    // Patches edited or removed since they were applied have to come out again
+    if !wanted.starts_with(&state.applied) {
+        let Some(base) = state.base.clone().filter(|_| git) else {
+            return Err(CrossDevError::PatchesChanged {
+                package: name.to_string(),
+                dir: dir.to_string(),
+            });
+        };
+
+        info(format!("🩹 Patches changed, resetting {dir} to {base}"));
+        let sh = Shell::new()?;
+        cmd!(sh, "git -C {dir} checkout --detach {base}").run_logged()?;
+        state.applied.clear();
+        state.head = Some(base);
+    }
+
+    for patch in wanted.into_iter().skip(state.applied.len()) {
+        info(format!("🩹 Applying {}", patch.name));
+        apply(name, dir, git, &patch.name)?;
+
+        state.applied.push(patch);
+        if git {
+            state.head = Some(git_head(dir)?);
+        }
+        write_state(dir, git, &state)?;
+    }
+
+    Ok(())
+}
+
+/// The commit a checkout had before its patches were applied, or `HEAD` if
+/// it has none, so that pins and updates look past the patch commits
+pub fn upstream_head(dir: &str) -> Result<String> {
+    match read_state(dir, true)?.base {
+        Some(base) => Ok(base),
+        None => git_head(dir),
+    }
+}
+
+/// Identifies the patches applied to a tree that isn't a checkout, whose
+/// patches don't show up in a commit
+pub fn applied_patches(dir: &str) -> Option<String> {
+    let state = fs::read_to_string(format!("{dir}/{STATE_FILE}")).ok()?;
+
+    Some(Fingerprint::new().add("patches", state).digest())
+}
+
+/// Inputs the patches add to the download phase: their names and contents
+pub fn patches_fingerprint(package: &Package) -> Result<String> {
+    let mut fingerprint = Fingerprint::new();
+
+    for patch in find_patches(package)? {
+        let sha256 = sha256_file(&patch)?;
+        fingerprint = fingerprint.add(&patch, sha256);
+    }
+
+    Ok(fingerprint.digest())
+}
+
+// Expands the `patches` globs, relative to the repository root, in the
+// configured order and sorted within each glob
+fn find_patches(package: &Package) -> Result<Vec<String>> {
+    let root_dir = get_dir(EnvType::RootDir)?;
+    let mut patches = Vec::new();
+
+    for pattern in &package.patches {
+        let pattern = expand_home(pattern)?;
+        let full = if Path::new(&pattern).is_absolute() {
+            pattern.clone()
+        } else {
+            format!("{root_dir}/{pattern}")
+        };
+        let bad_pattern = |message: String| CrossDevError::BadPatchPattern {
+            package: package.name.clone(),
+            pattern: pattern.clone(),
+            message,
+        };
+
+        let mut matches = glob::glob(&full)
+            .map_err(|err| bad_pattern(err.to_string()))?
+            .map(|path| path.map_err(|err| bad_pattern(err.to_string())))
+            .collect::<Result<Vec<_>>>()?;
+        if matches.is_empty() {
+            return Err(bad_pattern("no such file".to_string()));
+        }
+
+        matches.sort();
+        patches.extend(matches.iter().map(|path| path.display().to_string()));
+    }
+
+    Ok(patches)
+}
+
+fn apply(name: &str, dir: &str, git: bool, patch: &str) -> Result<()> {
+    let mut sh = Shell::new()?;
+    let failed = |err: CrossDevError| CrossDevError::PatchFailed {
+        package: name.to_string(),
+        patch: patch.to_string(),
+        reason: err.to_string(),
+    };
+
+    if git {
+        // `git am` commits, so it needs someone to commit as
+        if cmd!(sh, "git -C {dir} var GIT_COMMITTER_IDENT")
+            .output()
+            .is_err()
+        {
+            sh.set_var("GIT_COMMITTER_NAME", "crossdev");
+            sh.set_var("GIT_COMMITTER_EMAIL", "crossdev@localhost");
+        }
+
+        if let Err(err) = cmd!(sh, "git -C {dir} am --3way {patch}").run_logged() {
+            let _ = cmd!(sh, "git -C {dir} am --abort").output();
+            return Err(failed(err));
+        }
+    } else {
+        // Check first, so a patch that doesn't apply leaves the tree alone
+        cmd!(
+            sh,
+            "patch -p1 --forward --batch --dry-run -d {dir} -i {patch}"
+        )
+        .run_logged()
+        .map_err(failed)?;
+        cmd!(sh, "patch -p1 --forward --batch -d {dir} -i {patch}")
+            .run_logged()
+            .map_err(failed)?;
+    }
+
+    Ok(())
+}
+
+fn read_state(dir: &str, git: bool) -> Result<PatchState> {
+    let state: PatchState = match fs::read_to_string(state_path(dir, git)) {
+        Ok(json) => serde_json::from_str(&json)?,
+        Err(_) => PatchState::default(),
+    };
+    if !git {
+        return Ok(state);
+    }
+
+    // The checkout moved since, e.g. to a new pin, so none of the patches are on it
+    let head = git_head(dir)?;
+    if state.head.as_ref() == Some(&head) {
+        return Ok(state);
+    }
+
+    Ok(PatchState {
+        base: Some(head.clone()),
+        head: Some(head),
+        applied: Vec::new(),
+    })
+}
+
+fn write_state(dir: &str, git: bool, state: &PatchState) -> Result<()> {
+    fs::write(state_path(dir, git), serde_json::to_string_pretty(state)?)?;
+
+    Ok(())
+}
+
+fn state_path(dir: &str, git: bool) -> String {
+    if git {
+        format!("{dir}/{GIT_STATE_FILE}")
+    } else {
+        format!("{dir}/{STATE_FILE}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    // Patterns are relative to the root directory, so every test works in a
+    // directory of its own under it
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = Path::new(test_env_vars().root_dir()).join(name);
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        dir
+    }
+
+    fn package(download_type: &str, patches: &[String]) -> Package {
+        let download = match download_type {
+            "git" => "{ url = \"u\", branch = \"master\" }",
+            _ => "{ url = \"u\" }",
+        };
+        let mut package: Package = toml::from_str(&format!(
+            "name = \"hello\"\npack_type = \"custom\"\nversion = \"1\"\ndownload_type = \"{download_type}\"\ndownload = {download}\n"
+        ))
+        .unwrap();
+        package.patches = patches.to_vec();
+
+        package
+    }
+
+    // A patch in `git format-patch` form appending `line` to `file`, whose
+    // current last line is `last`
+    fn write_patch(path: &Path, last: &str, line: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(
+            path,
+            format!(
+                "From 0000000000000000000000000000000000000000 Mon Sep 17 00:00:00 2001\n\
+                 From: Test <test@localhost>\n\
+                 Date: Thu, 1 Jan 2026 00:00:00 +0000\n\
+                 Subject: [PATCH] add {line}\n\
+                 \n\
+                 ---\n\
+                 diff --git a/file b/file\n\
+                 --- a/file\n\
+                 +++ b/file\n\
+                 @@ -1 +1,2 @@\n \
+                 {last}\n\
+                 +{line}\n"
+            ),
+        )
+        .unwrap();
+    }
+
+    fn git(dir: &Path, args: &[&str]) {
+        let sh = Shell::new().unwrap();
+        cmd!(
+            sh,
+            "git -C {dir} -c user.name=Test -c user.email=test@localhost {args...}"
+        )
+        .output()
+        .unwrap();
+    }
+
+    #[test]
+    fn finds_patches_in_configured_order_sorted_within_each_glob() {
+        let dir = temp_dir("order");
+        for patch in ["late/2.patch", "late/1.patch", "early/9.patch"] {
+            write_patch(&dir.join(patch), "a", "b");
+        }
+
+        let patches = find_patches(&package(
+            "file",
+            &[
+                "order/early/*.patch".to_string(),
+                "order/late/*.patch".to_string(),
+            ],
+        ))
+        .unwrap();
+
+        let expected: Vec<_> = ["early/9.patch", "late/1.patch", "late/2.patch"]
+            .iter()
+            .map(|patch| dir.join(patch).display().to_string())
+            .collect();
+        assert_eq!(patches, expected);
+
+        let missing = find_patches(&package("file", &["order/none/*.patch".to_string()]));
+        assert!(matches!(
+            missing,
+            Err(CrossDevError::BadPatchPattern { .. })
+        ));
+    }
+
+    #[test]
+    fn skips_applied_patches_and_refuses_changed_ones_outside_git() {
+        let dir = temp_dir("plain");
+        let src = dir.join("src");
+        fs::create_dir_all(&src).unwrap();
+        fs::write(src.join("file"), "base\n").unwrap();
+        write_patch(&dir.join("patches/1.patch"), "base", "one");
+        let src = src.display().to_string();
+        let package = package("file", &["plain/patches/*.patch".to_string()]);
+
+        apply_patches(&package, &src).unwrap();
+        let applied = applied_patches(&src).unwrap();
+
+        // `patch --forward` fails on a patch that is already in, so this
+        // only passes if the patch is skipped
+        apply_patches(&package, &src).unwrap();
+        assert_eq!(
+            fs::read_to_string(format!("{src}/file")).unwrap(),
+            "base\none\n"
+        );
+        assert_eq!(applied_patches(&src).unwrap(), applied);
+
+        write_patch(&dir.join("patches/1.patch"), "base", "uno");
+        assert!(matches!(
+            apply_patches(&package, &src),
+            Err(CrossDevError::PatchesChanged { .. })
+        ));
+    }
+
+    #[test]
+    fn resets_a_checkout_when_a_patch_changes() {
+        let dir = temp_dir("git");
+        let src = dir.join("src");
+        fs::create_dir_all(&src).unwrap();
+        fs::write(src.join("file"), "base\n").unwrap();
+        git(&src, &["init", "-q"]);
+        git(&src, &["add", "file"]);
+        git(&src, &["commit", "-q", "-m", "base"]);
+        let src = src.display().to_string();
+        let base = git_head(&src).unwrap();
+
+        write_patch(&dir.join("patches/1.patch"), "base", "one");
+        let package = package("git", &["git/patches/*.patch".to_string()]);
+
+        apply_patches(&package, &src).unwrap();
+        let patched = git_head(&src).unwrap();
+        assert_ne!(patched, base);
+        assert_eq!(upstream_head(&src).unwrap(), base);
+
+        apply_patches(&package, &src).unwrap();
+        assert_eq!(git_head(&src).unwrap(), patched);
+
+        write_patch(&dir.join("patches/1.patch"), "base", "uno");
+        apply_patches(&package, &src).unwrap();
+        assert_eq!(
+            fs::read_to_string(format!("{src}/file")).unwrap(),
+            "base\nuno\n"
+        );
+        assert_eq!(upstream_head(&src).unwrap(), base);
+        assert_eq!(read_state(&src, true).unwrap().applied.len(), 1);
+    }
+}
@@ -1,40 +1,68 @@
-use crate::{config::*, env::*, error::Result, traits::Installable};
+use crate::{
+    archive::archive_stem,
+    config::*,
+    download::*,
+    env::*,
+    error::Result,
+    patch::apply_patches,
+    runner::{is_dry_run, make_dir, RunLogged},
+    stamp::*,
+    system::{check_system, locate, not_found, system_fingerprint},
+    traits::Installable,
+    utils::*,
+};
+use std::path::Path;
 use xshell::{cmd, Shell};
 
 #[derive(Debug)]
 pub struct Qemu {
     arch: String,
+    build_type: BuildType,
     package: Package,
     build_dir: String,
     qemu_dir: String,
     bin_name: String,
-    bin_path: String,
+    // None for a host QEMU that isn't installed
+    bin_path: Option<String>,
 }
 
 impl Qemu {
-    pub fn new(arch: String, package: Package) -> Result<Self> {
+    pub fn new(arch: String, build_type: BuildType, package: Package) -> Result<Self> {
         let qemu_dir_name = match &package.download {
-            Download::File(download) => {
-                let url = &download.url;
-                let filename = url.split('/').last().unwrap();
-                let filename_without_ext = filename.rsplitn(3, '.').last().unwrap();
-                filename_without_ext.to_string()
-            }
+            Download::File(download) => match &download.extract_dir {
+                Some(extract_dir) => extract_dir.clone(),
+                None => {
+                    let filename = download.url.split('/').next_back().unwrap();
+                    archive_stem(filename).to_string()
+                }
+            },
             Download::Git(git) => {
                 let url = &git.url;
-                let filename = url.split('/').last().unwrap();
+                let filename = url.split('/').next_back().unwrap();
                 filename.split('.').next().unwrap().to_string()
             }
-            _ => "".to_string(),
+            Download::Path(local) => local
+                .path
+                .trim_end_matches('/')
+                .split('/')
+                .next_back()
+                .unwrap()
+                .to_string(),
+            _ => "qemu".to_string(),
         };
 
         let build_dir = format!("{}/{qemu_dir_name}", get_dir(EnvType::BuildDir)?);
-        let qemu_dir = format!("{}/{qemu_dir_name}", get_dir(EnvType::DownloadDir)?);
+        let qemu_dir = source_dir(&package.download, &qemu_dir_name)?;
         let bin_name = format!("qemu-system-{arch}");
-        let bin_path = format!("{build_dir}/{bin_name}");
+        // A host QEMU runs from wherever it is installed
+        let bin_path = match &package.download {
+            Download::System(system) => locate(system, std::slice::from_ref(&bin_name)),
+            _ => Some(format!("{build_dir}/{bin_name}")),
+        };
 
         Ok(Self {
             arch,
+            build_type,
             package,
             build_dir,
             qemu_dir,
@@ -49,47 +77,75 @@ impl Installable for Qemu {
         &self.package.name
     }
 
+    fn pack_type(&self) -> PackType {
+        self.package.pack_type
+    }
+
     fn download(&self) -> Result<()> {
         match &self.package.download {
-            Download::File(download) => {
-                let url = &download.url;
-                let filename = url.split('/').last().unwrap();
-                let qemu_dir = &self.qemu_dir;
-
-                let mut sh = Shell::new()?;
-
-                if !sh.path_exists(qemu_dir) {
-                    sh.set_current_dir(get_dir(EnvType::DownloadDir)?);
-
-                    println!("📦 Downloading {url}...");
-
-                    cmd!(sh, "wget {url}").run_echo()?;
-                    cmd!(sh, "tar -xf {filename}").run_echo()?;
-                    cmd!(sh, "rm -f {filename}").run_echo()?;
-                }
+            Download::File(download) if is_dry_run() || !Path::new(&self.qemu_dir).exists() => {
+                extract_file(self.name(), download, &self.qemu_dir)?
+            }
+            Download::Path(local) => check_local_source(local)?,
+            Download::System(system) => {
+                check_system(self.name(), system, std::slice::from_ref(&self.bin_name))?;
             }
             _ => {}
         }
         Ok(())
     }
 
+    fn patch(&self) -> Result<()> {
+        apply_patches(&self.package, &self.qemu_dir)
+    }
+
+    fn update(&self) -> Result<()> {
+        match &self.package.download {
+            Download::File(file) => update_file(self.name(), file, &self.qemu_dir),
+            _ => self.download(),
+        }
+    }
+
     fn build(&self) -> Result<()> {
+        if let Download::System(_) = &self.package.download {
+            return Ok(());
+        }
+
         let mut sh = Shell::new()?;
 
         let qemu_dir = self.qemu_dir.as_str();
         let qemu_build_dir = self.build_dir.as_str();
-        let bin_path = self.bin_path.as_str();
-
-        if !sh.path_exists(bin_path) {
-            sh.create_dir(qemu_build_dir)?;
-            sh.set_current_dir(qemu_build_dir);
-
-            cmd!(sh, "{qemu_dir}/configure --target-list=riscv64-softmmu").run_echo()?;
-            let nproc = cmd!(sh, "nproc").read()?;
-            cmd!(sh, "make -j{nproc}").run_echo()?;
+        let bin_path = self.bin_path()?;
+
+        make_dir(qemu_build_dir)?;
+        sh.set_current_dir(qemu_build_dir);
+
+        let build = &self.package.build;
+        build.apply_env(&mut sh);
+        let make_vars = &build.make_vars();
+        let targets = &build.targets;
+
+        let mut configure_flags = build.configure_flags.clone();
+        if !configure_flags
+            .iter()
+            .any(|flag| flag.starts_with("--target-list"))
+        {
+            configure_flags.push(format!("--target-list={}-softmmu", self.arch));
         }
+        let debug_flags = match self.build_type {
+            BuildType::Debug => ["--enable-debug"],
+            BuildType::Release => ["--disable-debug-info"],
+        };
+
+        cmd!(
+            sh,
+            "{qemu_dir}/configure {configure_flags...} {debug_flags...}"
+        )
+        .run_logged()?;
+        let nproc = get_jobs()?;
+        cmd!(sh, "make -j{nproc} {make_vars...} {targets...}").run_logged()?;
 
-        cmd!(sh, "{bin_path} --version").run_echo()?;
+        cmd!(sh, "{bin_path} --version").run_logged()?;
 
         Ok(())
     }
@@ -98,6 +154,47 @@ impl Installable for Qemu {
         Ok(())
     }
 
+    fn clean(&self) -> Result<()> {
+        // QEMU configures out of tree, so its whole build directory is output
+        remove_dir(&self.build_dir)
+    }
+
+    fn distclean(&self) -> Result<()> {
+        self.clean()?;
+        remove_sources(&self.package.download, &self.qemu_dir)
+    }
+
+    fn fingerprint(&self, phase: Phase) -> Result<Option<Fingerprint>> {
+        let download = &self.package.download;
+        if let Download::System(system) = download {
+            return Ok(Some(system_fingerprint(system, self.bin_path()?)));
+        }
+
+        match phase {
+            Phase::Download => download_fingerprint(&self.package, &self.qemu_dir),
+            Phase::Build => {
+                let Some(revision) = source_revision(download, &self.qemu_dir)? else {
+                    return Ok(None);
+                };
+
+                Ok(Some(
+                    Fingerprint::new()
+                        .add("package", format!("{:?}", self.package))
+                        .add("revision", revision)
+                        .add("arch", &self.arch)
+                        .add("build_type", self.build_type)
+                        .add("output", file_signature(self.bin_path()?)),
+                ))
+            }
+            // QEMU runs from its build directory, there is nothing to install
+            Phase::Install => Ok(None),
+        }
+    }
+
+    fn source_dir(&self) -> &str {
+        &self.qemu_dir
+    }
+
     fn build_dir(&self) -> &str {
         &self.build_dir
     }
@@ -106,7 +203,37 @@ impl Installable for Qemu {
         &self.bin_name
     }
 
-    fn bin_path(&self) -> &str {
-        &self.bin_path
+    fn bin_path(&self) -> Result<&str> {
+        match (&self.bin_path, &self.package.download) {
+            (Some(bin_path), _) => Ok(bin_path),
+            (None, Download::System(system)) => Err(not_found(
+                self.name(),
+                system,
+                std::slice::from_ref(&self.bin_name),
+            )),
+            (None, _) => unreachable!("only a host QEMU has no path"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::CrossDevError;
+
+    #[test]
+    fn a_missing_host_qemu_has_no_path() {
+        test_env_vars();
+        let package: Package = toml::from_str(
+            "name = \"qemu\"\npack_type = \"qemu\"\nversion = \"1\"\ndownload_type = \"system\"\ndownload = { prefix = \"/nonexistent\" }\n",
+        )
+        .unwrap();
+
+        let qemu = Qemu::new("riscv64".to_string(), BuildType::Debug, package).unwrap();
+
+        assert!(matches!(
+            qemu.bin_path(),
+            Err(CrossDevError::SystemToolNotFound { .. })
+        ));
     }
 }
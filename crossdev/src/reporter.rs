@@ -0,0 +1,180 @@
+use crate::{runner::current_package, stamp::Phase};
+use serde::Serialize;
+use std::{
+    cell::RefCell,
+    fmt,
+    io::{self, Write},
+    sync::Arc,
+};
+
+thread_local! {
+    // Where this thread's events go, set by whoever runs the setup on it
+    static REPORTER: RefCell<Option<Arc<dyn Reporter>>> = const { RefCell::new(None) };
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// Progress of a setup, from packages down to single lines of command output
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    PackageStarted {
+        package: &'a str,
+    },
+    PhaseStarted {
+        package: &'a str,
+        phase: Phase,
+    },
+    PhaseSkipped {
+        package: &'a str,
+        phase: Phase,
+    },
+    CommandSpawned {
+        package: Option<&'a str>,
+        command: &'a str,
+    },
+    // A step a dry run would have taken, as a line of bash
+    CommandPlanned {
+        package: Option<&'a str>,
+        script: &'a str,
+    },
+    Output {
+        package: Option<&'a str>,
+        stream: Stream,
+        line: &'a str,
+    },
+    DownloadProgress {
+        package: Option<&'a str>,
+        url: &'a str,
+        downloaded: u64,
+        total: Option<u64>,
+    },
+    PhaseFinished {
+        package: &'a str,
+        phase: Phase,
+    },
+    PackageFinished {
+        package: &'a str,
+    },
+    Message {
+        package: Option<&'a str>,
+        message: &'a str,
+    },
+    Error {
+        package: Option<&'a str>,
+        message: &'a str,
+    },
+}
+
+/// Receives every event; packages may be set up on several threads at once
+pub trait Reporter: fmt::Debug + Send + Sync {
+    fn report(&self, event: &Event);
+
+    /// Whether stdout carries the events, so commands left on the terminal
+    /// must print to stderr instead
+    fn owns_stdout(&self) -> bool {
+        false
+    }
+}
+
+/// Human readable output, used unless another reporter is set
+#[derive(Debug, Default)]
+pub struct EmojiReporter;
+
+impl Reporter for EmojiReporter {
+    fn report(&self, event: &Event) {
+        match *event {
+            Event::PackageStarted { package } => println!("🚀 Preparing package: {package}"),
+            Event::PhaseStarted { package, phase } => match phase {
+                Phase::Download => {}
+                Phase::Build => println!("🔧 Building {package}"),
+                Phase::Install => println!("🚚 Installing {package}"),
+            },
+            Event::PhaseSkipped { package, phase } => {
+                println!("⏭️  {package} {phase} is up to date")
+            }
+            Event::CommandSpawned { command, .. } => eprintln!("$ {command}"),
+            Event::CommandPlanned { script, .. } => println!("{script}"),
+            Event::Output { stream, line, .. } => match stream {
+                Stream::Stdout => println!("{line}"),
+                Stream::Stderr => eprintln!("{line}"),
+            },
+            Event::DownloadProgress {
+                downloaded, total, ..
+            } => {
+                let mib = |bytes: u64| bytes as f64 / (1024.0 * 1024.0);
+                match total {
+                    Some(total) => println!(
+                        "📥 {:.1} / {:.1} MiB ({}%)",
+                        mib(downloaded),
+                        mib(total),
+                        downloaded * 100 / total.max(1)
+                    ),
+                    None => println!("📥 {:.1} MiB", mib(downloaded)),
+                }
+            }
+            Event::PhaseFinished { package, phase } => match phase {
+                Phase::Download => {}
+                Phase::Build => println!("✨ {package} build complete!"),
+                Phase::Install => println!("✨ {package} installation complete!"),
+            },
+            Event::PackageFinished { package } => {
+                println!("✅ Package {package} prepared successfully")
+            }
+            Event::Message { message, .. } => println!("{message}"),
+            // The error itself is returned, and printed by whoever ran the setup
+            Event::Error { .. } => {}
+        }
+    }
+}
+
+/// One JSON object per line on stdout, for CI and IDE integrations
+#[derive(Debug, Default)]
+pub struct JsonReporter;
+
+impl Reporter for JsonReporter {
+    fn report(&self, event: &Event) {
+        if let Ok(json) = serde_json::to_string(event) {
+            let _ = writeln!(io::stdout().lock(), "{json}");
+        }
+    }
+
+    fn owns_stdout(&self) -> bool {
+        true
+    }
+}
+
+/// Runs `run` with every event reported on this thread going to `reporter`
+pub fn reporting_to<T>(reporter: &Arc<dyn Reporter>, run: impl FnOnce() -> T) -> T {
+    let previous = REPORTER.replace(Some(reporter.clone()));
+    let result = run();
+    REPORTER.set(previous);
+
+    result
+}
+
+/// The reporter of this thread, to hand on to threads it spawns
+pub fn current_reporter() -> Arc<dyn Reporter> {
+    REPORTER
+        .with_borrow(|reporter| reporter.clone())
+        .unwrap_or_else(|| Arc::new(EmojiReporter))
+}
+
+pub fn report(event: Event) {
+    current_reporter().report(&event);
+}
+
+/// Reports a status message on behalf of the package set up on this thread
+pub fn info(message: impl AsRef<str>) {
+    let package = current_package();
+
+    report(Event::Message {
+        package: package.as_deref(),
+        message: message.as_ref(),
+    });
+}
@@ -0,0 +1,620 @@
+use crate::{
+    archive::{self, Format},
+    env::*,
+    error::*,
+    reporter::{current_reporter, info, report, reporting_to, Event, Stream},
+    stamp::Phase,
+};
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    env,
+    fs::{self, File},
+    io::{self, Read, Write},
+    os::unix::fs::PermissionsExt,
+    path::Path,
+    process::Stdio,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use xshell::Cmd;
+
+const LOG_DIR: &str = "logs";
+// How much of a failed command's output ends up in its error
+const TAIL_LINES: usize = 20;
+const TAIL_BYTES: usize = 64 * 1024;
+
+// Steps recorded instead of run, present only in dry-run mode
+static PLAN: Mutex<Option<Vec<Step>>> = Mutex::new(None);
+static OFFLINE: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    // Phase running on this thread, packages may be set up in parallel
+    static PHASE: RefCell<Option<PhaseContext>> = const { RefCell::new(None) };
+}
+
+struct PhaseContext {
+    package: String,
+    log: Option<PhaseLog>,
+}
+
+#[derive(Clone)]
+struct PhaseLog {
+    path: String,
+    file: Arc<Mutex<File>>,
+}
+
+// Everything a command printed so far
+struct Output {
+    log: Option<PhaseLog>,
+    tail: VecDeque<u8>,
+}
+
+impl Output {
+    fn record(&mut self, bytes: &[u8]) {
+        if let Some(log) = &self.log {
+            // A log that can't be written shouldn't fail the build itself
+            let _ = log.file.lock().unwrap().write_all(bytes);
+        }
+
+        self.tail.extend(bytes);
+        let excess = self.tail.len().saturating_sub(TAIL_BYTES);
+        self.tail.drain(..excess);
+    }
+
+    fn tail_lines(&self) -> Vec<String> {
+        let tail: Vec<u8> = self.tail.iter().copied().collect();
+        let tail = String::from_utf8_lossy(&tail);
+        let lines: Vec<&str> = tail
+            .split(['\n', '\r'])
+            .filter(|line| !line.trim().is_empty())
+            .collect();
+
+        lines[lines.len().saturating_sub(TAIL_LINES)..]
+            .iter()
+            .map(|line| line.to_string())
+            .collect()
+    }
+}
+
+/// Something a dry run would have done, as it appears in the emitted script
+#[derive(Clone, Debug)]
+pub enum Step {
+    Phase {
+        package: String,
+        phase: Phase,
+    },
+    Run {
+        dir: String,
+        env: Vec<(String, String)>,
+        command: Vec<String>,
+    },
+    Write {
+        path: String,
+        contents: String,
+    },
+}
+
+impl Step {
+    pub fn to_script(&self) -> String {
+        match self {
+            Step::Phase { package, phase } => format!("\n# {package}: {phase}"),
+            Step::Run { dir, env, command } => {
+                let env: Vec<_> = env
+                    .iter()
+                    .map(|(key, value)| quote(&format!("{key}={value}")))
+                    .collect();
+                let env = if env.is_empty() {
+                    String::new()
+                } else {
+                    format!("env {} ", env.join(" "))
+                };
+                let command: Vec<_> = command.iter().map(|arg| quote(arg)).collect();
+
+                format!("(cd {} && {env}{})", quote(dir), command.join(" "))
+            }
+            Step::Write { path, contents } => {
+                let dir = Path::new(path)
+                    .parent()
+                    .map_or(".".to_string(), |dir| dir.display().to_string());
+                let newline = if contents.ends_with('\n') { "" } else { "\n" };
+
+                format!(
+                    "mkdir -p {} && cat > {} <<'XDEV_EOF'\n{contents}{newline}XDEV_EOF",
+                    quote(&dir),
+                    quote(path)
+                )
+            }
+        }
+    }
+}
+
+/// From now on, commands and file changes are recorded and printed instead of run
+pub fn enable_dry_run() {
+    PLAN.lock().unwrap().get_or_insert_with(Vec::new);
+}
+
+pub fn is_dry_run() -> bool {
+    PLAN.lock().unwrap().is_some()
+}
+
+/// From now on, every step that needs the network fails instead
+pub fn enable_offline() {
+    OFFLINE.store(true, Ordering::Relaxed);
+}
+
+pub fn is_offline() -> bool {
+    OFFLINE.load(Ordering::Relaxed)
+}
+
+/// Writes the steps recorded so far as a standalone bash script
+pub fn emit_script<P>(path: P) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    let plan = PLAN.lock().unwrap();
+    let mut script = String::from(
+        "#!/usr/bin/env bash\n# Generated by `xdev --dry-run`, replays the setup without xdev\nset -euo pipefail\n",
+    );
+    for step in plan.iter().flatten() {
+        script.push_str(&step.to_script());
+        script.push('\n');
+    }
+
+    fs::write(&path, script)?;
+    let mut permissions = fs::metadata(&path)?.permissions();
+    permissions.set_mode(permissions.mode() | 0o111);
+    fs::set_permissions(&path, permissions)?;
+
+    Ok(())
+}
+
+fn record(step: Step) {
+    if let Some(plan) = PLAN.lock().unwrap().as_mut() {
+        let package = current_package();
+        report(Event::CommandPlanned {
+            package: package.as_deref(),
+            script: &step.to_script(),
+        });
+        plan.push(step);
+    }
+}
+
+/// Name of the package whose phase is running on this thread
+pub fn current_package() -> Option<String> {
+    PHASE.with_borrow(|phase| phase.as_ref().map(|phase| phase.package.clone()))
+}
+
+fn with_phase<T>(context: PhaseContext, run: impl FnOnce() -> Result<T>) -> Result<T> {
+    let previous = PHASE.replace(Some(context));
+    let result = run();
+    PHASE.set(previous);
+
+    result
+}
+
+/// Writes `contents` to `path`, or records it in a dry run
+pub fn write_file(path: &str, contents: String) -> Result<()> {
+    if is_dry_run() {
+        record(Step::Write {
+            path: path.to_string(),
+            contents,
+        });
+        return Ok(());
+    }
+
+    fs::write(path, contents)?;
+
+    Ok(())
+}
+
+/// Creates `dir` and its parents, or records it in a dry run
+pub fn make_dir(dir: &str) -> Result<()> {
+    if is_dry_run() {
+        record(Step::Run {
+            dir: env::current_dir()?.display().to_string(),
+            env: Vec::new(),
+            command: vec!["mkdir".to_string(), "-p".to_string(), dir.to_string()],
+        });
+        return Ok(());
+    }
+
+    fs::create_dir_all(dir)?;
+
+    Ok(())
+}
+
+/// Copies `from` to `to`, or records it in a dry run
+pub fn copy_file(from: &str, to: &str) -> Result<()> {
+    info(format!("📦 Copying {from} to {to}..."));
+
+    if is_dry_run() {
+        record(Step::Run {
+            dir: env::current_dir()?.display().to_string(),
+            env: Vec::new(),
+            command: vec!["cp".to_string(), from.to_string(), to.to_string()],
+        });
+        return Ok(());
+    }
+
+    fs::copy(from, to)?;
+
+    Ok(())
+}
+
+/// Extracts `archive` into `dest`, or records the equivalent command in a dry run
+pub fn extract_archive(archive: &str, dest: &str, strip_components: usize) -> Result<()> {
+    info(format!("📦 Extracting {archive} to {dest}..."));
+
+    if is_dry_run() {
+        record(Step::Run {
+            dir: env::current_dir()?.display().to_string(),
+            env: Vec::new(),
+            command: vec![
+                "sh".to_string(),
+                "-c".to_string(),
+                extract_command(archive, dest, strip_components)?,
+            ],
+        });
+        return Ok(());
+    }
+
+    archive::extract(archive, dest, strip_components)
+}
+
+// Shell command extracting `archive` into `dest` like `archive::extract` does
+fn extract_command(archive: &str, dest: &str, strip_components: usize) -> Result<String> {
+    let format = Format::detect(archive)?;
+    let (archive, dest) = (quote(archive), quote(dest));
+    let n = strip_components;
+    // `find` counts the directory it starts from as depth 0
+    let depth = n + 1;
+
+    let command = match format {
+        Format::Zip if n == 0 => format!("unzip -q {archive} -d {dest}"),
+        // unzip can't strip leading directories, so the archive is unpacked
+        // aside and what lies `n` directories deep is copied over
+        Format::Zip => format!(
+            "tmp=$(mktemp -d) && unzip -q {archive} -d \"$tmp\" && \
+             find \"$tmp\" -mindepth {depth} -maxdepth {depth} -exec cp -a {{}} {dest}/ \\; && \
+             rm -rf \"$tmp\""
+        ),
+        _ => format!("tar -xf {archive} -C {dest} --strip-components={n}"),
+    };
+
+    Ok(format!("mkdir -p {dest} && {command}"))
+}
+
+/// Runs `run` with the output of every command it runs on this thread also
+/// written to `<work_dir>/logs/<name>-<phase>-<timestamp>.log`
+pub fn with_phase_log<T>(name: &str, phase: Phase, run: impl FnOnce() -> Result<T>) -> Result<T> {
+    if is_dry_run() {
+        let context = PhaseContext {
+            package: name.to_string(),
+            log: None,
+        };
+        return with_phase(context, || {
+            record(Step::Phase {
+                package: name.to_string(),
+                phase,
+            });
+            run()
+        });
+    }
+
+    let log_dir = format!("{}/{LOG_DIR}", get_dir(EnvType::WorkDir)?);
+    fs::create_dir_all(&log_dir)?;
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or_default();
+    let path = format!("{log_dir}/{name}-{phase}-{timestamp}.log");
+    let file = Arc::new(Mutex::new(File::create(&path)?));
+
+    let context = PhaseContext {
+        package: name.to_string(),
+        log: Some(PhaseLog {
+            path: path.clone(),
+            file,
+        }),
+    };
+    let result = with_phase(context, run);
+
+    // Phases that ran no commands leave no log behind
+    if fs::metadata(&path).is_ok_and(|metadata| metadata.len() == 0) {
+        fs::remove_file(&path)?;
+    }
+
+    result
+}
+
+pub trait RunLogged {
+    /// Like `run_echo`, but tees the output into the current phase log and
+    /// reports the end of the output when the command fails. Dry runs only
+    /// record the command.
+    fn run_logged(self) -> Result<()>;
+
+    /// Like `run_logged`, but leaves the command on the terminal for commands
+    /// that prompt or draw progress there, like `sudo` or `git clone`. The
+    /// phase log only notes that the command ran.
+    fn run_interactive(self) -> Result<()>;
+}
+
+impl RunLogged for Cmd {
+    fn run_logged(self) -> Result<()> {
+        if is_dry_run() {
+            return record_command(&self);
+        }
+
+        let command = self.to_string();
+        let package = current_package();
+        let output = Mutex::new(Output {
+            log: PHASE.with_borrow(|phase| phase.as_ref().and_then(|phase| phase.log.clone())),
+            tail: VecDeque::new(),
+        });
+
+        report(Event::CommandSpawned {
+            package: package.as_deref(),
+            command: &command,
+        });
+        output
+            .lock()
+            .unwrap()
+            .record(format!("$ {command}\n").as_bytes());
+
+        let mut child = self
+            .to_command()
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let stderr = child.stderr.take().expect("stderr is piped");
+
+        let package = package.as_deref();
+        let reporter = current_reporter();
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                reporting_to(&reporter, || {
+                    forward(stdout, Stream::Stdout, package, &output)
+                })
+            });
+            scope.spawn(|| {
+                reporting_to(&reporter, || {
+                    forward(stderr, Stream::Stderr, package, &output)
+                })
+            });
+        });
+
+        let status = child.wait()?;
+        if status.success() {
+            return Ok(());
+        }
+
+        let output = output.into_inner().unwrap();
+        Err(CrossDevError::CommandFailed {
+            command,
+            status: status.to_string(),
+            tail: output.tail_lines(),
+            log: output.log.as_ref().map(|log| log.path.clone()),
+        })
+    }
+
+    fn run_interactive(self) -> Result<()> {
+        if is_dry_run() {
+            return record_command(&self);
+        }
+
+        let command = self.to_string();
+        let package = current_package();
+        let log = PHASE.with_borrow(|phase| phase.as_ref().and_then(|phase| phase.log.clone()));
+        if let Some(log) = &log {
+            let note = format!("$ {command}\n(ran on the terminal, output not captured)\n");
+            let _ = log.file.lock().unwrap().write_all(note.as_bytes());
+        }
+
+        report(Event::CommandSpawned {
+            package: package.as_deref(),
+            command: &command,
+        });
+
+        let mut command_line = self.to_command();
+        if current_reporter().owns_stdout() {
+            command_line.stdout(io::stderr());
+        }
+        let status = command_line.status()?;
+        if status.success() {
+            return Ok(());
+        }
+
+        Err(CrossDevError::CommandFailed {
+            command,
+            status: status.to_string(),
+            tail: Vec::new(),
+            log: log.map(|log| log.path),
+        })
+    }
+}
+
+// Records `cmd` as a step of the dry run instead of running it
+fn record_command(cmd: &Cmd) -> Result<()> {
+    let command = cmd.to_command();
+    record(Step::Run {
+        dir: command
+            .get_current_dir()
+            .map_or(env::current_dir()?, Path::to_path_buf)
+            .display()
+            .to_string(),
+        env: command
+            .get_envs()
+            .filter_map(|(key, value)| {
+                Some((
+                    key.to_string_lossy().into(),
+                    value?.to_string_lossy().into(),
+                ))
+            })
+            .collect(),
+        command: std::iter::once(command.get_program())
+            .chain(command.get_args())
+            .map(|arg| arg.to_string_lossy().to_string())
+            .collect(),
+    });
+
+    Ok(())
+}
+
+// Single-quotes `arg` for bash unless it is plainly safe
+pub(crate) fn quote(arg: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "_@%+=:,./-".contains(c);
+
+    if !arg.is_empty() && arg.chars().all(safe) {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+// Reports a child's stream line by line as it arrives
+fn forward(mut from: impl Read, stream: Stream, package: Option<&str>, output: &Mutex<Output>) {
+    let mut buf = [0; 8192];
+    let mut line = Vec::new();
+    let report_line = |line: &mut Vec<u8>| {
+        report(Event::Output {
+            package,
+            stream,
+            line: &String::from_utf8_lossy(line),
+        });
+        line.clear();
+    };
+
+    while let Ok(len) = from.read(&mut buf) {
+        if len == 0 {
+            break;
+        }
+        output.lock().unwrap().record(&buf[..len]);
+
+        for &byte in &buf[..len] {
+            match byte {
+                b'\n' | b'\r' if line.is_empty() => {}
+                b'\n' | b'\r' => report_line(&mut line),
+                _ => line.push(byte),
+            }
+        }
+    }
+
+    if !line.is_empty() {
+        report_line(&mut line);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quotes_only_what_bash_would_split_or_expand() {
+        assert_eq!(quote("make"), "make");
+        assert_eq!(quote("O=/tmp/build-1.0"), "O=/tmp/build-1.0");
+        assert_eq!(quote(""), "''");
+        assert_eq!(quote("make -C $SOURCE_DIR"), "'make -C $SOURCE_DIR'");
+        assert_eq!(quote("it's"), r"'it'\''s'");
+        assert_eq!(quote("*.patch"), "'*.patch'");
+    }
+
+    #[test]
+    fn runs_commands_in_their_directory_with_their_env() {
+        let step = Step::Run {
+            dir: "/tmp/my build".to_string(),
+            env: vec![("CFLAGS".to_string(), "-O2 -g".to_string())],
+            command: vec!["make".to_string(), "-j4".to_string()],
+        };
+        assert_eq!(
+            step.to_script(),
+            "(cd '/tmp/my build' && env 'CFLAGS=-O2 -g' make -j4)"
+        );
+
+        let step = Step::Run {
+            dir: "/tmp".to_string(),
+            env: Vec::new(),
+            command: vec!["sh".to_string(), "-c".to_string(), "echo $HOME".to_string()],
+        };
+        assert_eq!(step.to_script(), "(cd /tmp && sh -c 'echo $HOME')");
+    }
+
+    #[test]
+    fn writes_files_through_a_quoted_heredoc() {
+        let step = Step::Write {
+            path: "/tmp/out/fragment.config".to_string(),
+            contents: "CONFIG_KASAN=y".to_string(),
+        };
+
+        assert_eq!(
+            step.to_script(),
+            "mkdir -p /tmp/out && cat > /tmp/out/fragment.config <<'XDEV_EOF'\nCONFIG_KASAN=y\nXDEV_EOF"
+        );
+    }
+
+    // Every file under `dir` with its contents, relative to `dir`
+    fn tree(dir: &Path) -> Vec<(String, String)> {
+        let mut files = Vec::new();
+        let mut dirs = vec![dir.to_path_buf()];
+        while let Some(current) = dirs.pop() {
+            for entry in fs::read_dir(current).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    dirs.push(path);
+                } else {
+                    let name = path.strip_prefix(dir).unwrap().display().to_string();
+                    files.push((name, fs::read_to_string(&path).unwrap()));
+                }
+            }
+        }
+        files.sort();
+
+        files
+    }
+
+    #[test]
+    fn scripted_zip_extraction_strips_like_the_real_one() {
+        let dir = std::env::temp_dir().join(format!("crossdev-runner-{}-zip", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let archive = dir.join("it's.zip");
+        let mut zip = zip::ZipWriter::new(File::create(&archive).unwrap());
+        for (name, contents) in [
+            ("pkg-1.0/bin/tool", "tool"),
+            ("pkg-1.0/.config", "config"),
+            ("pkg-1.0/README", "readme"),
+            ("stray", "dropped"),
+        ] {
+            zip.start_file(name, zip::write::SimpleFileOptions::default())
+                .unwrap();
+            zip.write_all(contents.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+        let archive = archive.display().to_string();
+
+        for strip_components in [0, 1] {
+            let scripted = dir.join(format!("scripted {strip_components}"));
+            let extracted = dir.join(format!("extracted {strip_components}"));
+            let command =
+                extract_command(&archive, &scripted.display().to_string(), strip_components)
+                    .unwrap();
+            let status = std::process::Command::new("sh")
+                .args(["-c", &command])
+                .status()
+                .unwrap();
+            assert!(status.success(), "{command}");
+
+            fs::create_dir_all(&extracted).unwrap();
+            archive::extract(&archive, &extracted.display().to_string(), strip_components).unwrap();
+
+            assert_eq!(tree(&scripted), tree(&extracted));
+        }
+    }
+}
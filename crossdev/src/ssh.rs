@@ -1,6 +1,10 @@
-use crate::{error::*, utils::*};
-use std::{fs::File, io::Write, process::Command};
-use xshell::Shell;
+use crate::{
+    error::*,
+    reporter::info,
+    runner::{quote, RunLogged},
+};
+use std::process::Command;
+use xshell::{cmd, Shell};
 
 const OVERLAY_DIR: &str = "custom_buildroot/board/overlay";
 const SSH_DIR: &str = "root/.ssh";
@@ -16,38 +20,20 @@ pub fn prepare_ssh_key(download_dir: &str) -> Result<()> {
     let ssh_pub = format!("{ssh_key}.pub");
     let authorized_keys = format!("{ssh_dir}/authorized_keys");
 
-    create_dir(&ssh_dir)?;
-
     if sh.path_exists(&ssh_key) && sh.path_exists(&authorized_keys) {
-        println!("SSH key already exists");
+        info("SSH key already exists");
         return Ok(());
     }
 
     // Generate SSH key without passphrase
-    let status = Command::new("ssh-keygen")
-        .arg("-t")
-        .arg("rsa")
-        .arg("-N")
-        .arg("")
-        .arg("-f")
-        .arg(ssh_key.as_str())
-        .status()?;
-
-    if !status.success() {
-        return Err(CrossDevError::SshKeyError);
-    }
-
-    // Create the authorized_keys file if it doesn't exist
-    if !sh.path_exists(&authorized_keys) {
-        File::create(&authorized_keys)?;
-    }
+    cmd!(sh, "mkdir -p {ssh_dir}").run_logged()?;
+    cmd!(sh, "ssh-keygen -t rsa -N '' -f {ssh_key}").run_logged()?;
 
     // Append the public key to the authorized_keys file
-    let mut file = File::options().append(true).open(&authorized_keys)?;
-    let pub_key = std::fs::read_to_string(&ssh_pub)?;
-    writeln!(file, "{}", pub_key)?;
+    let append = format!("cat {} >> {}", quote(&ssh_pub), quote(&authorized_keys));
+    cmd!(sh, "sh -c {append}").run_logged()?;
 
-    println!("✅ SSH key is ready!");
+    info("✅ SSH key is ready!");
 
     Ok(())
 }
@@ -0,0 +1,208 @@
+use crate::{env::*, error::*, utils::create_dir};
+use core::fmt;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::{
+    fs,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const STAMP_DIR: &str = ".stamps";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Phase {
+    Download,
+    Build,
+    Install,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Phase::Download => write!(f, "download"),
+            Phase::Build => write!(f, "build"),
+            Phase::Install => write!(f, "install"),
+        }
+    }
+}
+
+/// The inputs a phase depends on; the phase is skipped while they don't change
+#[derive(Debug, Default)]
+pub struct Fingerprint {
+    inputs: Vec<String>,
+}
+
+impl Fingerprint {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(mut self, key: &str, value: impl fmt::Display) -> Self {
+        self.inputs.push(format!("{key}={value}"));
+        self
+    }
+
+    pub fn digest(&self) -> String {
+        let mut hasher = Sha256::new();
+        for input in &self.inputs {
+            hasher.update(input.as_bytes());
+            hasher.update(b"\n");
+        }
+
+        format!("{:x}", hasher.finalize())
+    }
+}
+
+pub fn stamp_path(name: &str, phase: Phase) -> Result<String> {
+    Ok(format!(
+        "{}/{STAMP_DIR}/{name}-{phase}.stamp",
+        get_dir(EnvType::BuildDir)?
+    ))
+}
+
+pub fn is_fresh(name: &str, phase: Phase, fingerprint: &Fingerprint) -> Result<bool> {
+    let stamp = fs::read_to_string(stamp_path(name, phase)?).unwrap_or_default();
+
+    Ok(stamp.trim() == fingerprint.digest())
+}
+
+pub fn write_stamp(name: &str, phase: Phase, fingerprint: &Fingerprint) -> Result<()> {
+    create_dir(&format!("{}/{STAMP_DIR}", get_dir(EnvType::BuildDir)?))?;
+    fs::write(stamp_path(name, phase)?, fingerprint.digest())?;
+
+    Ok(())
+}
+
+pub fn remove_stamps(name: &str) -> Result<()> {
+    for phase in [Phase::Download, Phase::Build, Phase::Install] {
+        let path = stamp_path(name, phase)?;
+        if Path::new(&path).exists() {
+            fs::remove_file(path)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Inputs of an install phase that copies `bin_path` to `image_path`
+pub fn install_fingerprint(bin_path: &str, image_path: &str) -> Fingerprint {
+    Fingerprint::new()
+        .add("binary", file_signature(bin_path))
+        .add("image", file_signature(image_path))
+}
+
+/// Hash of every file below `dir` (names and contents), for inputs like overlays
+pub fn hash_dir(dir: &str) -> Result<String> {
+    let mut files = Vec::new();
+    collect_files(Path::new(dir), &mut files)?;
+    files.sort();
+
+    let mut hasher = Sha256::new();
+    for file in files {
+        hasher.update(file.strip_prefix(dir).unwrap_or(&file).as_bytes());
+        hasher.update(fs::read(&file)?);
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Cheap identity of a file (size and modification time), `missing` if absent
+pub fn file_signature(path: &str) -> String {
+    let Ok(metadata) = fs::metadata(path) else {
+        return "missing".to_string();
+    };
+    let modified = metadata
+        .modified()
+        .unwrap_or(SystemTime::UNIX_EPOCH)
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_nanos())
+        .unwrap_or_default();
+
+    format!("{}:{modified}", metadata.len())
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<String>) -> Result<()> {
+    if !dir.is_dir() {
+        return Ok(());
+    }
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_files(&path, files)?;
+        } else {
+            files.push(path.to_string_lossy().to_string());
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("crossdev-stamp-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        dir
+    }
+
+    #[test]
+    fn digest_depends_on_values_and_order() {
+        let digest = |inputs: &[(&str, &str)]| {
+            inputs
+                .iter()
+                .fold(Fingerprint::new(), |fingerprint, (key, value)| {
+                    fingerprint.add(key, value)
+                })
+                .digest()
+        };
+
+        let base = digest(&[("package", "linux"), ("revision", "v6.6")]);
+        assert_eq!(base, digest(&[("package", "linux"), ("revision", "v6.6")]));
+        assert_ne!(base, digest(&[("package", "linux"), ("revision", "v6.7")]));
+        assert_ne!(base, digest(&[("revision", "v6.6"), ("package", "linux")]));
+        assert_ne!(base, digest(&[("package", "linux")]));
+    }
+
+    #[test]
+    fn file_signature_tracks_rewrites() {
+        let dir = temp_dir("signature");
+        let file = dir.join("Image").display().to_string();
+
+        assert_eq!(file_signature(&file), "missing");
+
+        fs::write(&file, b"kernel").unwrap();
+        let first = file_signature(&file);
+        assert_ne!(first, "missing");
+        assert_eq!(first, file_signature(&file));
+
+        fs::write(&file, b"rebuilt kernel").unwrap();
+        assert_ne!(first, file_signature(&file));
+    }
+
+    #[test]
+    fn hash_dir_covers_names_and_contents() {
+        let dir = temp_dir("hash");
+        let root = dir.display().to_string();
+        fs::create_dir_all(dir.join("etc")).unwrap();
+        fs::write(dir.join("etc/hostname"), b"crossdev").unwrap();
+
+        let first = hash_dir(&root).unwrap();
+        assert_eq!(first, hash_dir(&root).unwrap());
+
+        fs::write(dir.join("etc/hostname"), b"riscv").unwrap();
+        let edited = hash_dir(&root).unwrap();
+        assert_ne!(first, edited);
+
+        fs::rename(dir.join("etc/hostname"), dir.join("etc/hosts")).unwrap();
+        assert_ne!(edited, hash_dir(&root).unwrap());
+    }
+}
@@ -0,0 +1,75 @@
+use crate::{
+    config::PackType,
+    env::EnvVars,
+    error::*,
+    lock::locked_package,
+    stamp::{stamp_path, Phase},
+    traits::Installable,
+};
+use serde::Serialize;
+use std::{fs, path::Path, time::UNIX_EPOCH};
+
+/// Where everything lives and how far each package got
+#[derive(Debug, Serialize)]
+pub struct Status<'a> {
+    pub env: &'a EnvVars,
+    pub packages: Vec<PackageStatus>,
+}
+
+/// `built`/`installed` are `None` for packages without that step
+#[derive(Debug, Serialize)]
+pub struct PackageStatus {
+    pub name: String,
+    pub pack_type: PackType,
+    pub version: String,
+    pub source_dir: String,
+    pub downloaded: bool,
+    pub built: Option<bool>,
+    pub installed: Option<bool>,
+    pub commit: Option<String>,
+    pub sha256: Option<String>,
+    pub bin_path: Option<String>,
+    pub bin_size: Option<u64>,
+    pub image_path: Option<String>,
+    /// Seconds since the Unix epoch
+    pub last_build: Option<u64>,
+}
+
+impl PackageStatus {
+    pub fn new(package: &dyn Installable, version: &str) -> Result<Self> {
+        // A host tool that isn't installed has no binary to show either
+        let bin_path = package
+            .bin_path()
+            .ok()
+            .filter(|path| !path.is_empty())
+            .map(str::to_string);
+        let bin = bin_path.as_ref().and_then(|path| fs::metadata(path).ok());
+        let image_path = package.image_path()?;
+        let locked = locked_package(package.name());
+
+        // The build stamp is written once a build succeeds; fall back to the binary
+        let build_stamp = stamp_path(package.name(), Phase::Build)?;
+        let last_build = bin
+            .as_ref()
+            .map(|bin| fs::metadata(build_stamp).unwrap_or_else(|_| bin.clone()))
+            .and_then(|metadata| metadata.modified().ok())
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|time| time.as_secs());
+
+        Ok(Self {
+            name: package.name().to_string(),
+            pack_type: package.pack_type(),
+            version: version.to_string(),
+            source_dir: package.source_dir().to_string(),
+            downloaded: Path::new(package.source_dir()).exists(),
+            built: bin_path.as_ref().map(|_| bin.is_some()),
+            installed: image_path.as_ref().map(|path| Path::new(path).exists()),
+            commit: locked.as_ref().and_then(|source| source.commit.clone()),
+            sha256: locked.and_then(|source| source.sha256),
+            bin_size: bin.map(|metadata| metadata.len()),
+            bin_path,
+            image_path,
+            last_build,
+        })
+    }
+}
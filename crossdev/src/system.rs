@@ -0,0 +1,214 @@
+use crate::{
+    config::SystemDownload,
+    error::*,
+    reporter::info,
+    stamp::{file_signature, Fingerprint},
+    utils::expand_home,
+};
+use std::{
+    cmp::Ordering,
+    env,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+use xshell::{cmd, Shell};
+
+/// Path of the executable a `system` download uses: `binary` (or the first of
+/// `defaults` found) in `<prefix>/bin`, or on `PATH` without a prefix
+pub fn locate(system: &SystemDownload, defaults: &[String]) -> Option<String> {
+    let binaries = match &system.binary {
+        Some(binary) => vec![binary.clone()],
+        None => defaults.to_vec(),
+    };
+    let dirs: Vec<PathBuf> = match &system.prefix {
+        Some(prefix) => {
+            let prefix = PathBuf::from(expand_home(prefix).ok()?);
+            vec![prefix.join("bin"), prefix]
+        }
+        None => env::split_paths(&env::var_os("PATH")?).collect(),
+    };
+
+    binaries.iter().find_map(|binary| {
+        // An absolute `binary` is used as is
+        if Path::new(binary).is_absolute() {
+            return is_executable(Path::new(binary)).then(|| binary.clone());
+        }
+
+        dirs.iter()
+            .map(|dir| dir.join(binary))
+            .find(|path| is_executable(path))
+            .map(|path| path.display().to_string())
+    })
+}
+
+/// Locates the executable of package `name` and makes sure it is at least
+/// `min_version`, returning its path
+pub fn check_system(name: &str, system: &SystemDownload, defaults: &[String]) -> Result<String> {
+    let Some(path) = locate(system, defaults) else {
+        return Err(not_found(name, system, defaults));
+    };
+
+    let found = match &system.min_version {
+        Some(required) => check_version(name, &path, required)?,
+        None => tool_version(&path),
+    };
+
+    info(format!(
+        "🖥️  Using {path}{}",
+        found.map(|found| format!(" ({found})")).unwrap_or_default()
+    ));
+
+    Ok(path)
+}
+
+/// The error for a `system` download whose executable `locate` can't find
+pub fn not_found(name: &str, system: &SystemDownload, defaults: &[String]) -> CrossDevError {
+    let binary = system
+        .binary
+        .clone()
+        .unwrap_or_else(|| defaults.join(" or "));
+    let place = match &system.prefix {
+        Some(prefix) => format!("{prefix}/bin"),
+        None => "PATH".to_string(),
+    };
+
+    CrossDevError::SystemToolNotFound {
+        package: name.to_string(),
+        binary,
+        place,
+    }
+}
+
+/// Makes sure the tool at `path` is at least version `required`, returning
+/// the version found
+pub fn check_version(name: &str, path: &str, required: &str) -> Result<Option<String>> {
+    let found = tool_version(path);
+    let recent = found
+        .as_deref()
+        .is_some_and(|found| compare_versions(found, required) != Ordering::Less);
+
+    if !recent {
+        return Err(CrossDevError::SystemToolTooOld {
+            package: name.to_string(),
+            path: path.to_string(),
+            found: found.unwrap_or_else(|| "unknown".to_string()),
+            required: required.to_string(),
+        });
+    }
+
+    Ok(found)
+}
+
+/// Inputs of every phase of a package using `path` from the host
+pub fn system_fingerprint(system: &SystemDownload, path: &str) -> Fingerprint {
+    Fingerprint::new()
+        .add("system", format!("{system:?}"))
+        .add("path", path)
+        .add("binary", file_signature(path))
+}
+
+fn tool_version(path: &str) -> Option<String> {
+    let sh = Shell::new().ok()?;
+    let output = cmd!(sh, "{path} --version").output().ok()?;
+
+    parse_version(&String::from_utf8_lossy(&output.stdout))
+}
+
+// The first version number in `--version` output, e.g. 8.2.2 out of
+// "QEMU emulator version 8.2.2 (Debian 1:8.2.2+ds-0ubuntu1)". Tools without a
+// vendor string, like upstream LLVM, only print it on the second line.
+fn parse_version(output: &str) -> Option<String> {
+    output
+        .split_whitespace()
+        .map(|word| {
+            word.trim_start_matches(['(', 'v'])
+                .split(|c: char| !c.is_ascii_digit() && c != '.')
+                .next()
+                .unwrap_or_default()
+                .trim_end_matches('.')
+        })
+        .find(|version| version.contains('.') && version.starts_with(|c: char| c.is_ascii_digit()))
+        .map(str::to_string)
+}
+
+// Compares dotted versions numerically, missing parts counting as 0
+fn compare_versions(a: &str, b: &str) -> Ordering {
+    let parts = |version: &str| -> Vec<u64> {
+        version
+            .split('.')
+            .map(|part| part.parse().unwrap_or(0))
+            .collect()
+    };
+    let (a, b) = (parts(a), parts(b));
+
+    (0..a.len().max(b.len()))
+        .map(|index| {
+            let part = |parts: &[u64]| parts.get(index).copied().unwrap_or(0);
+            part(&a).cmp(&part(&b))
+        })
+        .find(|ordering| ordering.is_ne())
+        .unwrap_or(Ordering::Equal)
+}
+
+pub fn is_executable(path: &Path) -> bool {
+    path.metadata()
+        .is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn parses_the_version_tools_print() {
+        let outputs = [
+            (
+                "QEMU emulator version 8.2.2 (Debian 1:8.2.2+ds-0ubuntu1)\nCopyright (c) 2003-2023\n",
+                "8.2.2",
+            ),
+            ("riscv64-linux-gnu-gcc (Ubuntu 13.2.0-4ubuntu3) 13.2.0\n", "13.2.0"),
+            ("Ubuntu clang version 18.1.3 (1ubuntu1)\nTarget: x86_64\n", "18.1.3"),
+            ("Ubuntu LLD 18.1.3 (compatible with GNU linkers)\n", "18.1.3"),
+            (
+                "LLVM (http://llvm.org/):\n  LLVM version 18.1.8\n  Optimized build.\n",
+                "18.1.8",
+            ),
+        ];
+
+        for (output, version) in outputs {
+            assert_eq!(parse_version(output).as_deref(), Some(version), "{output}");
+        }
+        assert_eq!(parse_version("no version here\n"), None);
+    }
+
+    #[test]
+    fn compares_versions_numerically() {
+        assert_eq!(compare_versions("8.2.2", "8.2.2"), Ordering::Equal);
+        assert_eq!(compare_versions("8.10", "8.9.1"), Ordering::Greater);
+        assert_eq!(compare_versions("13.2", "13.2.0"), Ordering::Equal);
+        assert_eq!(compare_versions("18.1.3", "18.1.10"), Ordering::Less);
+    }
+
+    #[test]
+    fn checks_the_version_of_a_tool() {
+        let dir = std::env::temp_dir().join(format!("crossdev-system-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let tool = dir.join("qemu-system-riscv64");
+        fs::write(
+            &tool,
+            "#!/bin/sh\necho 'QEMU emulator version 8.2.2 (Debian 1:8.2.2+ds-0ubuntu1)'\n",
+        )
+        .unwrap();
+        fs::set_permissions(&tool, fs::Permissions::from_mode(0o755)).unwrap();
+        let tool = tool.display().to_string();
+
+        assert_eq!(tool_version(&tool).as_deref(), Some("8.2.2"));
+        assert!(check_version("qemu", &tool, "8.2").is_ok());
+        assert!(matches!(
+            check_version("qemu", &tool, "9.0"),
+            Err(CrossDevError::SystemToolTooOld { .. })
+        ));
+    }
+}
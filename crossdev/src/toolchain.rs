@@ -1,42 +1,145 @@
-use crate::{config::*, env::*, error::*, traits::Installable, utils::*};
+use crate::{
+    config::*,
+    download::*,
+    error::*,
+    reporter::info,
+    runner::{is_dry_run, RunLogged},
+    system::{check_system, check_version, is_executable, locate},
+    traits::Installable,
+    utils::*,
+};
+use std::path::Path;
 use xshell::{cmd, Shell};
 
 const TOOLCHAIN_DIR: &str = "toolchains";
 
+// What an LLVM build takes from the toolchain, all from clang's directory
+const LLVM_TOOLS: [&str; 3] = ["clang", "ld.lld", "llvm-ar"];
+// Oldest LLVM the kernel can be built with
+const LLVM_MIN_VERSION: &str = "13.0.1";
+
 #[derive(Debug)]
 pub struct Toolchain {
     arch: String,
+    flavor: ToolchainFlavor,
     package: Package,
     toolchain_dir: String,
 }
 
 impl Toolchain {
-    pub fn new(arch: String, package: Package) -> Result<Self> {
-        let toolchain_dir = format!("{}/{}", get_dir(EnvType::DownloadDir)?, TOOLCHAIN_DIR);
+    pub fn new(arch: String, flavor: ToolchainFlavor, package: Package) -> Result<Self> {
+        let toolchain_dir = source_dir(&package.download, TOOLCHAIN_DIR)?;
 
         Ok(Self {
             arch,
+            flavor,
             toolchain_dir,
             package,
         })
     }
 
     fn find_toolchain(&self) -> Result<String> {
+        // Installed on the host, so it is on PATH or under the given prefix
+        let host = match &self.package.download {
+            Download::System(system) => Some(system.clone()),
+            Download::Apt(_) => Some(SystemDownload::default()),
+            _ => None,
+        };
+        if let Some(system) = host {
+            return Ok(locate(&system, &self.system_compiler()).unwrap_or_default());
+        }
+
         let sh = Shell::new()?;
 
         let arch_name = &self.arch;
-        let find_str = format!("*{arch_name}*-gcc");
+        let find_str = match self.flavor {
+            ToolchainFlavor::Gnu => format!("*{arch_name}*-gcc"),
+            ToolchainFlavor::Llvm => "clang".to_string(),
+        };
         let toolchain_dir = self.toolchain_dir.as_str();
 
         // Ensure the toolchain directory exists
         if !sh.path_exists(toolchain_dir) {
+            if is_dry_run() {
+                return Ok(String::new());
+            }
             sh.create_dir(toolchain_dir)?;
         }
 
-        let toolchain = cmd!(sh, "find {toolchain_dir} -name {find_str}").read()?;
+        let toolchain = cmd!(sh, "find {toolchain_dir} -name {find_str} -not -type d").read()?;
 
         Ok(toolchain)
     }
+
+    // Where extracting the toolchain archive will put the compiler; the
+    // archive must be in the cache already since a dry run downloads nothing
+    fn planned_toolchain(&self) -> Result<Option<String>> {
+        let Download::File(file) = &self.package.download else {
+            return Ok(None);
+        };
+        let Some(files) = archive_files(file)? else {
+            info(format!(
+                "❌ {} isn't downloaded yet, run `xdev toolchain` once so a dry run can see where it puts the compiler",
+                file.url
+            ));
+            return Ok(None);
+        };
+
+        Ok(files
+            .iter()
+            .find(|path| self.is_compiler(path))
+            .map(|path| format!("{}/{}", self.toolchain_dir, path.display())))
+    }
+
+    // Whether `path` is what `find_toolchain` looks for
+    fn is_compiler(&self, path: &Path) -> bool {
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy())
+            .unwrap_or_default();
+
+        match self.flavor {
+            ToolchainFlavor::Gnu => name.contains(self.arch.as_str()) && name.ends_with("-gcc"),
+            ToolchainFlavor::Llvm => name == "clang",
+        }
+    }
+
+    // The compiler the cross prefix is taken from
+    fn compiler(&self) -> &'static str {
+        match self.flavor {
+            ToolchainFlavor::Gnu => "gcc",
+            ToolchainFlavor::Llvm => "clang",
+        }
+    }
+
+    // Names distributions give their cross gcc, clang targets every arch itself
+    fn system_compiler(&self) -> Vec<String> {
+        let arch = &self.arch;
+        match self.flavor {
+            ToolchainFlavor::Gnu => vec![
+                format!("{arch}-linux-gnu-gcc"),
+                format!("{arch}-unknown-linux-gnu-gcc"),
+            ],
+            ToolchainFlavor::Llvm => vec!["clang".to_string()],
+        }
+    }
+
+    // The kernel and OpenSBI pick the LLVM tools up from clang's directory
+    fn check_llvm(&self, prefix: &str) -> Result<()> {
+        for tool in LLVM_TOOLS {
+            let path = format!("{prefix}{tool}");
+            if !is_executable(Path::new(&path)) {
+                return Err(CrossDevError::SystemToolNotFound {
+                    package: self.name().to_string(),
+                    binary: tool.to_string(),
+                    place: prefix.trim_end_matches('/').to_string(),
+                });
+            }
+            check_version(self.name(), &path, LLVM_MIN_VERSION)?;
+        }
+
+        Ok(())
+    }
 }
 
 impl Installable for Toolchain {
@@ -44,61 +147,81 @@ impl Installable for Toolchain {
         &self.package.name
     }
 
+    fn pack_type(&self) -> PackType {
+        self.package.pack_type
+    }
+
     fn download(&self) -> Result<()> {
         match &self.package.download {
             Download::File(download) => {
-                let url = &download.url;
-                let filename = url.split('/').last().unwrap();
-                let download_dir = get_dir(EnvType::DownloadDir)?;
-                let toolchain_dir = self.toolchain_dir.as_str();
-
-                let mut sh = Shell::new()?;
-
-                let toolchain = self.find_toolchain()?;
-                if toolchain.is_empty() {
-                    sh.create_dir(toolchain_dir)?;
-                    sh.set_current_dir(download_dir.as_str());
-
-                    println!("📦 Downloading {url}...");
-
-                    cmd!(sh, "wget -O {filename} {url}").run_echo()?;
-                    cmd!(sh, "tar -xf {filename} -C {toolchain_dir}").run_echo()?;
-                    cmd!(sh, "rm -f {filename}").run_echo()?;
+                // A dry run records the download either way, the script starts from scratch
+                if is_dry_run() || self.find_toolchain()?.is_empty() {
+                    extract_file(self.name(), download, &self.toolchain_dir)?;
                 }
             }
             Download::Apt(download) => {
                 let package_name = &download.package_name;
-                println!("📦 Installing {package_name} via APT...");
+                ensure_online(self.name(), &format!("apt package {package_name}"))?;
+                info(format!("📦 Installing {package_name} via APT..."));
                 let sh = Shell::new()?;
-                cmd!(sh, "sudo apt update").run_echo()?;
-                cmd!(sh, "sudo apt install -y {package_name}").run_echo()?;
+                cmd!(sh, "sudo apt update").run_interactive()?;
+                cmd!(sh, "sudo apt install -y {package_name}").run_interactive()?;
+            }
+            Download::Path(local) => {
+                check_local_source(local)?;
             }
             Download::Git(download) => {
-                let url = &download.url;
-                let branch = &download.branch;
-                println!("📦 Cloning {url} (branch: {branch})...");
-                let sh = Shell::new()?;
-                cmd!(sh, "git clone --branch {branch} {url}").run_echo()?;
+                git_clone(self.name(), download, &self.toolchain_dir)?;
+            }
+            Download::System(system) => {
+                check_system(self.name(), system, &self.system_compiler())?;
             }
         }
 
         Ok(())
     }
 
+    fn update(&self) -> Result<()> {
+        match &self.package.download {
+            Download::Git(git) => git_update(self.name(), git, &self.toolchain_dir),
+            Download::File(file) => update_file(self.name(), file, &self.toolchain_dir),
+            _ => self.download(),
+        }
+    }
+
     fn build(&self) -> Result<()> {
         let sh = Shell::new()?;
 
-        let toolchain = self.find_toolchain()?;
-        if toolchain.is_empty() {
-            println!("❌ Toolchain not found!");
-            return Err(CrossDevError::GccNotFound);
+        let mut toolchain = self.find_toolchain()?;
+        if toolchain.is_empty() && is_dry_run() {
+            // Nothing was extracted, so look where the archive puts the compiler
+            toolchain = self.planned_toolchain()?.ok_or(match self.flavor {
+                ToolchainFlavor::Gnu => CrossDevError::GccNotFound,
+                ToolchainFlavor::Llvm => CrossDevError::ClangNotFound,
+            })?;
+        } else if toolchain.is_empty() {
+            info("❌ Toolchain not found!");
+            return Err(match self.flavor {
+                ToolchainFlavor::Gnu => CrossDevError::GccNotFound,
+                ToolchainFlavor::Llvm => CrossDevError::ClangNotFound,
+            });
         }
 
-        set_cross_toolchain_path(toolchain.replace("gcc", ""))?;
+        // Only the trailing compiler name, a system prefix may well have it in a directory name
+        let compiler = self.compiler();
+        let prefix = toolchain
+            .trim()
+            .strip_suffix(compiler)
+            .unwrap_or(&toolchain);
+        set_cross_toolchain_path(prefix.to_string())?;
+
+        if self.flavor == ToolchainFlavor::Llvm && !is_dry_run() {
+            self.check_llvm(prefix)?;
+        }
 
         // Test toolchain
         let cross_toolchain = get_cross_toolchain_path()?;
-        cmd!(sh, "{cross_toolchain}gcc --version").run_echo()?;
+        cmd!(sh, "{cross_toolchain}{compiler} --version").run_logged()?;
 
         Ok(())
     }
@@ -107,15 +230,32 @@ impl Installable for Toolchain {
         Ok(())
     }
 
+    fn clean(&self) -> Result<()> {
+        // Prebuilt toolchains have no build output
+        Ok(())
+    }
+
+    fn distclean(&self) -> Result<()> {
+        match &self.package.download {
+            // Packages installed through apt or by hand are left to the system
+            Download::Apt(_) | Download::System(_) => Ok(()),
+            download => remove_sources(download, &self.toolchain_dir),
+        }
+    }
+
+    fn source_dir(&self) -> &str {
+        &self.toolchain_dir
+    }
+
     fn build_dir(&self) -> &str {
-        "".into()
+        ""
     }
 
     fn bin_name(&self) -> &str {
-        "".into()
+        ""
     }
 
-    fn bin_path(&self) -> &str {
-        "".into()
+    fn bin_path(&self) -> Result<&str> {
+        Ok("")
     }
 }
@@ -1,30 +1,112 @@
-use crate::error::Result;
+use crate::{
+    config::PackType,
+    error::Result,
+    reporter::{report, Event},
+    runner::{is_dry_run, with_phase_log},
+    stamp::{is_fresh, remove_stamps, write_stamp, Fingerprint, Phase},
+};
 use std::fmt::Debug;
 
-pub trait Installable: Debug {
+pub trait Installable: Debug + Send + Sync {
     fn name(&self) -> &str;
+    fn pack_type(&self) -> PackType;
     fn download(&self) -> Result<()>;
+
+    // Applies the config's `patches` to the downloaded sources
+    fn patch(&self) -> Result<()> {
+        Ok(())
+    }
+
     fn build(&self) -> Result<()>;
     fn install(&self) -> Result<()>;
 
+    // Removes the build output so the next setup rebuilds from the sources
+    fn clean(&self) -> Result<()>;
+
+    // Also removes the downloaded sources, which the next setup fetches again
+    fn distclean(&self) -> Result<()>;
+
+    // Moves the sources to their latest upstream revision, ignoring any lockfile pin.
+    // Sources that can't move, like local trees and host tools, are only checked again.
+    fn update(&self) -> Result<()> {
+        self.download()
+    }
+
+    // Package types that have to be set up first, on top of the config's `depends_on`
+    fn dependencies(&self) -> Vec<PackType> {
+        Vec::new()
+    }
+
+    // Inputs of `phase`, skipped while they match its stamp; `None` always runs it
+    fn fingerprint(&self, _phase: Phase) -> Result<Option<Fingerprint>> {
+        Ok(None)
+    }
+
+    // Where the package's image is installed, if it installs one
+    fn image_path(&self) -> Result<Option<String>> {
+        Ok(None)
+    }
+
+    fn source_dir(&self) -> &str;
     fn build_dir(&self) -> &str;
     fn bin_name(&self) -> &str;
-    fn bin_path(&self) -> &str;
+    fn bin_path(&self) -> Result<&str>;
+
+    // Runs `phase` unless it is up to date, and returns whether it ran
+    fn run_phase(&self, phase: Phase, run: &dyn Fn() -> Result<()>) -> Result<bool> {
+        let package = self.name();
 
-    fn setup(&self) -> Result<()> {
-        println!("🚀 Preparing package: {}", self.name());
+        // A dry run plans every phase and leaves the stamps alone
+        if !is_dry_run() {
+            if let Some(fingerprint) = self.fingerprint(phase)? {
+                if is_fresh(package, phase, &fingerprint)? {
+                    report(Event::PhaseSkipped { package, phase });
+                    return Ok(false);
+                }
+            }
+        }
+
+        report(Event::PhaseStarted { package, phase });
+        if let Err(err) = with_phase_log(package, phase, run) {
+            report(Event::Error {
+                package: Some(package),
+                message: &err.to_string(),
+            });
+            return Err(err);
+        }
+        report(Event::PhaseFinished { package, phase });
+
+        if is_dry_run() {
+            return Ok(true);
+        }
+
+        // Stamp the inputs as the phase left them, e.g. a freshly cloned revision
+        if let Some(fingerprint) = self.fingerprint(phase)? {
+            write_stamp(self.name(), phase, &fingerprint)?;
+        }
+
+        Ok(true)
+    }
 
-        self.download()?;
+    fn setup(&self, force: bool) -> Result<()> {
+        report(Event::PackageStarted {
+            package: self.name(),
+        });
 
-        println!("🔧 Building {}", self.name());
-        self.build()?;
-        println!("✨ {} build complete!", self.name());
+        if force && !is_dry_run() {
+            remove_stamps(self.name())?;
+        }
 
-        println!("🚚 Installing {}", self.name());
-        self.install()?;
-        println!("✨ {} installation complete!", self.name());
+        self.run_phase(Phase::Download, &|| {
+            self.download()?;
+            self.patch()
+        })?;
+        self.run_phase(Phase::Build, &|| self.build())?;
+        self.run_phase(Phase::Install, &|| self.install())?;
 
-        println!("✅ Package {} prepared successfully", self.name());
+        report(Event::PackageFinished {
+            package: self.name(),
+        });
 
         Ok(())
     }
@@ -1,11 +1,16 @@
-use crate::error::*;
-use std::{env, fs, path::Path, sync::OnceLock};
+use crate::{error::*, reporter::info, runner::write_file};
+use std::{cell::Cell, env, fs, path::Path, sync::OnceLock};
 use xshell::{cmd, Shell};
 
 const DEFAULT_ROOT_DIR: &str = ".crossdev";
 
 static CROSS_TOOLCHAIN_PATH: OnceLock<String> = OnceLock::new();
 
+thread_local! {
+    // Share of the `--jobs` budget given to the package built on this thread
+    static JOBS: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
 pub fn get_root_dir() -> Result<String> {
     let sh = Shell::new()?;
     let output = cmd!(sh, "git rev-parse --show-toplevel").read()?;
@@ -21,6 +26,16 @@ pub fn get_work_dir() -> Result<String> {
     Ok(work_dir)
 }
 
+/// Expands a leading `~` to the home directory
+pub fn expand_home(path: &str) -> Result<String> {
+    match path.strip_prefix('~') {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => {
+            Ok(format!("{}{rest}", env::var("HOME")?))
+        }
+        _ => Ok(path.to_string()),
+    }
+}
+
 pub fn check_config_file(config: &str, default_config: &str) -> Result<()> {
     let config_file = Path::new(config);
     let default_config_file = Path::new(default_config);
@@ -46,14 +61,74 @@ pub fn get_cross_toolchain_path() -> Result<&'static str> {
         .ok_or(CrossDevError::CrossToolchainPathNotSet)
 }
 
+/// `PATH` with the cross toolchain's directory in front, for `LLVM=1` style
+/// builds that call clang and its tools by name
+pub fn llvm_search_path() -> Result<String> {
+    let llvm_dir = get_cross_toolchain_path()?.trim_end_matches('/');
+    let path = env::var("PATH")?;
+
+    Ok(format!("{llvm_dir}:{path}"))
+}
+
+/// Maps a toolchain arch (e.g. `riscv64`) to the kernel's `ARCH` value
+pub fn kernel_arch(arch: &str) -> &str {
+    if arch.starts_with("riscv") {
+        "riscv"
+    } else {
+        arch
+    }
+}
+
+/// Writes a kconfig fragment enabling or disabling each of the given symbols
+pub fn write_kconfig_fragment(path: &str, symbols: &[(&str, bool)]) -> Result<()> {
+    let content: String = symbols
+        .iter()
+        .map(|(symbol, enabled)| {
+            if *enabled {
+                format!("{symbol}=y\n")
+            } else {
+                format!("# {symbol} is not set\n")
+            }
+        })
+        .collect();
+
+    write_file(path, content)
+}
+
+pub fn set_jobs(jobs: Option<usize>) {
+    JOBS.with(|cell| cell.set(jobs));
+}
+
+/// Parallel make jobs for the current thread, defaulting to `nproc`
+pub fn get_jobs() -> Result<String> {
+    match JOBS.with(|cell| cell.get()) {
+        Some(jobs) => Ok(jobs.to_string()),
+        None => {
+            let sh = Shell::new()?;
+            Ok(cmd!(sh, "nproc").read()?.trim().to_string())
+        }
+    }
+}
+
+pub fn remove_dir(dir: &str) -> Result<()> {
+    let path = Path::new(dir);
+
+    if path.exists() {
+        info(format!("Removing directory: {}", dir));
+        fs::remove_dir_all(path)?;
+    }
+
+    Ok(())
+}
+
 pub fn create_dir(dir: &str) -> Result<()> {
     let path = Path::new(dir);
 
     if !path.exists() {
-        println!("Creating directory: {}", dir);
+        info(format!("Creating directory: {}", dir));
         fs::create_dir_all(path)?;
     } else {
-        println!("Directory already exists: {}", dir);
+        info(format!("Directory already exists: {}", dir));
     }
 
     // Verify the directory was created
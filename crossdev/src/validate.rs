@@ -0,0 +1,821 @@
+use crate::{
+    config::{merge_config_sources, read_config_chain, Config, ConfigSource, PackType},
+    interpolate::interpolate_packages,
+    utils::expand_home,
+};
+use color_eyre::eyre::Result;
+use core::fmt;
+use serde::Deserialize;
+use std::{
+    ops::Range,
+    path::{Component, Path, PathBuf},
+};
+use toml::Spanned;
+
+const SUPPORTED_ARCHES: &[&str] = &["riscv64"];
+
+const GIT_REFS: &[&str] = &["branch", "tag", "rev"];
+
+// Everything a system download may set, all of it optional
+const SYSTEM_KEYS: &[&str] = &["binary", "prefix", "min_version"];
+
+const REQUIRED_PACK_TYPES: &[PackType] = &[
+    PackType::Toolchain,
+    PackType::Qemu,
+    PackType::Opensbi,
+    PackType::Linux,
+    PackType::Buildroot,
+];
+
+/// A single problem found in a config file, pointing at the offending location.
+#[derive(Debug, Clone)]
+pub struct ConfigIssue {
+    pub file: PathBuf,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}: {}",
+            self.file.display(),
+            self.line,
+            self.column,
+            self.message
+        )
+    }
+}
+
+/// Every problem found in a config file, reported as a single error
+#[derive(Debug)]
+pub struct ConfigIssues {
+    pub path: PathBuf,
+    pub issues: Vec<ConfigIssue>,
+}
+
+impl fmt::Display for ConfigIssues {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} problem(s) found in {}",
+            self.issues.len(),
+            self.path.display()
+        )?;
+        for issue in &self.issues {
+            write!(f, "\n❌ {issue}")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigIssues {}
+
+// Loosely typed mirror of `Config` that keeps the source spans around
+#[derive(Deserialize)]
+struct RawConfig {
+    arch: Option<Spanned<String>>,
+    sanitizers: Option<Spanned<toml::Value>>,
+    #[serde(default)]
+    packages: Vec<Spanned<RawPackage>>,
+}
+
+#[derive(Deserialize)]
+struct RawPackage {
+    name: Option<Spanned<String>>,
+    pack_type: Option<Spanned<String>>,
+    download_type: Option<Spanned<String>>,
+    download: Option<Spanned<toml::Table>>,
+}
+
+// Index into the inheritance chain plus the byte span within that file
+type Location = (usize, Range<usize>);
+
+// Where each part of a package was last defined along the inheritance chain
+#[derive(Default)]
+struct PackageLocations {
+    entry: Option<Location>,
+    pack_type: Option<Location>,
+    download_type: Option<Location>,
+    download: Option<Location>,
+}
+
+struct Validator<'a> {
+    sources: &'a [ConfigSource],
+    issues: Vec<ConfigIssue>,
+}
+
+impl<'a> Validator<'a> {
+    fn report(&mut self, location: Location, message: String) {
+        let (index, span) = location;
+        let source = &self.sources[index];
+        let (line, column) = line_col(&source.content, span.start);
+
+        self.issues.push(ConfigIssue {
+            file: source.path.clone(),
+            line,
+            column,
+            message,
+        });
+    }
+
+    // Issues that don't belong to a specific key are reported at the top of the last file
+    fn top(&self) -> Location {
+        (self.sources.len() - 1, 0..0)
+    }
+
+    fn check_arch(&mut self, arch: Option<&str>, location: Option<Location>) {
+        let location = location.unwrap_or_else(|| self.top());
+
+        match arch {
+            Some(arch) if !SUPPORTED_ARCHES.contains(&arch) => self.report(
+                location,
+                format!(
+                    "unknown arch \"{arch}\" (supported: {})",
+                    SUPPORTED_ARCHES.join(", ")
+                ),
+            ),
+            Some(_) => {}
+            None => self.report(location, "missing `arch`".to_string()),
+        }
+    }
+
+    fn check_sanitizers(&mut self, config: &toml::Table, location: Option<Location>) {
+        let Some(sanitizers) = config.get("sanitizers").and_then(|s| s.as_array()) else {
+            return;
+        };
+        let location = location.unwrap_or_else(|| self.top());
+        let enabled = |name: &str| sanitizers.iter().any(|s| s.as_str() == Some(name));
+
+        if enabled("kasan") && enabled("kcsan") {
+            self.report(
+                location.clone(),
+                "the kasan and kcsan sanitizers cannot be enabled together".to_string(),
+            );
+        }
+
+        if !sanitizers.is_empty()
+            && config.get("build_type").and_then(|b| b.as_str()) == Some("release")
+        {
+            self.report(
+                location,
+                "sanitizers are only applied to debug builds".to_string(),
+            );
+        }
+    }
+
+    fn check_package(
+        &mut self,
+        name: &str,
+        package: &toml::Table,
+        locations: &PackageLocations,
+    ) -> Option<PackType> {
+        let entry = locations.entry.clone().unwrap_or_else(|| self.top());
+        let at = |location: &Option<Location>| location.clone().unwrap_or_else(|| entry.clone());
+
+        let pack_type = match package.get("pack_type").and_then(|value| value.as_str()) {
+            Some(pack_type) => {
+                match toml::Value::String(pack_type.to_string()).try_into::<PackType>() {
+                    Ok(pack_type) => Some(pack_type),
+                    Err(_) => {
+                        self.report(
+                            at(&locations.pack_type),
+                            format!("package \"{name}\": unknown pack_type \"{pack_type}\""),
+                        );
+                        None
+                    }
+                }
+            }
+            None => {
+                self.report(
+                    entry.clone(),
+                    format!("package \"{name}\": missing `pack_type`"),
+                );
+                None
+            }
+        };
+
+        match (pack_type, package.contains_key("script")) {
+            (Some(PackType::Custom), false) => self.report(
+                entry.clone(),
+                format!("package \"{name}\": custom packages need a `script` table"),
+            ),
+            (Some(pack_type), true) if pack_type != PackType::Custom => self.report(
+                entry.clone(),
+                format!("package \"{name}\": `script` is only used by custom packages"),
+            ),
+            _ => {}
+        }
+
+        let download_type = package
+            .get("download_type")
+            .and_then(|value| value.as_str());
+        let download = package.get("download").and_then(|value| value.as_table());
+
+        if package.contains_key("patches") {
+            match (pack_type, download_type) {
+                (Some(PackType::Toolchain), _) => self.report(
+                    entry.clone(),
+                    format!("package \"{name}\": toolchains can't be patched"),
+                ),
+                (_, Some("apt" | "path" | "system")) => self.report(
+                    entry.clone(),
+                    format!("package \"{name}\": `patches` only apply to git and file downloads"),
+                ),
+                _ => {}
+            }
+        }
+
+        if download_type == Some("system")
+            && pack_type.is_some_and(|pack_type| {
+                !matches!(
+                    pack_type,
+                    PackType::Toolchain | PackType::Qemu | PackType::Custom
+                )
+            })
+        {
+            self.report(
+                at(&locations.download_type),
+                format!("package \"{name}\": only toolchain, qemu and custom packages can use the system's tools"),
+            );
+        }
+
+        match (download_type, download) {
+            // A host tool is found on PATH without any settings
+            (Some("system"), None) => {}
+            (Some(download_type), Some(download)) => self.check_download(
+                name,
+                download_type,
+                download,
+                at(&locations.download_type),
+                at(&locations.download),
+            ),
+            (None, _) => self.report(
+                entry,
+                format!("package \"{name}\": missing `download_type`"),
+            ),
+            (Some(_), None) => {
+                self.report(entry, format!("package \"{name}\": missing `download`"))
+            }
+        }
+
+        pack_type
+    }
+
+    fn check_download(
+        &mut self,
+        name: &str,
+        kind: &str,
+        table: &toml::Table,
+        kind_at: Location,
+        table_at: Location,
+    ) {
+        let (required, optional): (&[&str], &[&str]) = match kind {
+            "git" => (
+                &["url"],
+                &["branch", "tag", "rev", "depth", "submodules", "mirrors"],
+            ),
+            "file" => (
+                &["url"],
+                &[
+                    "mirrors",
+                    "sha256",
+                    "sha512",
+                    "extract_dir",
+                    "strip_components",
+                ],
+            ),
+            "apt" => (&["package_name"], &[]),
+            "path" => (&["path"], &[]),
+            "system" => (&[], SYSTEM_KEYS),
+            other => {
+                self.report(
+                    kind_at,
+                    format!("package \"{name}\": unknown download_type \"{other}\""),
+                );
+                return;
+            }
+        };
+
+        for key in required {
+            if !table.contains_key(*key) {
+                self.report(
+                    table_at.clone(),
+                    format!(
+                        "package \"{name}\": download_type = \"{kind}\" requires `{key}` in `download`"
+                    ),
+                );
+            }
+        }
+
+        if kind == "git" {
+            let refs: Vec<_> = GIT_REFS
+                .iter()
+                .filter(|key| table.contains_key(**key))
+                .collect();
+
+            if refs.is_empty() {
+                self.report(
+                    table_at.clone(),
+                    format!("package \"{name}\": download_type = \"git\" requires one of `branch`, `tag` or `rev` in `download`"),
+                );
+            } else if table.contains_key("tag") && table.contains_key("rev") {
+                self.report(
+                    table_at.clone(),
+                    format!("package \"{name}\": `tag` and `rev` can't both be set"),
+                );
+            }
+
+            if table
+                .get("depth")
+                .is_some_and(|depth| depth.as_integer().is_none_or(|depth| depth < 0))
+            {
+                self.report(
+                    table_at.clone(),
+                    format!("package \"{name}\": `depth` must be a number of commits, 0 for the full history"),
+                );
+            }
+            if table
+                .get("submodules")
+                .is_some_and(|submodules| !submodules.is_bool())
+            {
+                self.report(
+                    table_at.clone(),
+                    format!("package \"{name}\": `submodules` must be true or false"),
+                );
+            }
+        }
+
+        for (key, digits) in [("sha256", 64), ("sha512", 128)] {
+            let Some(value) = table.get(key).filter(|_| kind == "file") else {
+                continue;
+            };
+            let valid = value.as_str().is_some_and(|value| {
+                value.len() == digits && value.chars().all(|c| c.is_ascii_hexdigit())
+            });
+
+            if !valid {
+                self.report(
+                    table_at.clone(),
+                    format!("package \"{name}\": `{key}` must be {digits} hex digits"),
+                );
+            }
+        }
+
+        if kind == "file" {
+            if table
+                .get("strip_components")
+                .is_some_and(|strip| strip.as_integer().is_none_or(|strip| strip < 0))
+            {
+                self.report(
+                    table_at.clone(),
+                    format!(
+                        "package \"{name}\": `strip_components` must be a number of directories"
+                    ),
+                );
+            }
+            if let Some(dir) = table.get("extract_dir") {
+                let relative = dir.as_str().is_some_and(|dir| {
+                    !dir.is_empty()
+                        && Path::new(dir)
+                            .components()
+                            .all(|component| matches!(component, Component::Normal(_)))
+                });
+
+                if !relative {
+                    self.report(
+                        table_at.clone(),
+                        format!("package \"{name}\": `extract_dir` must be a directory name inside the download directory"),
+                    );
+                }
+            }
+        }
+
+        if let Some(version) = table.get("min_version").filter(|_| kind == "system") {
+            let valid = version.as_str().is_some_and(|version| {
+                version
+                    .split('.')
+                    .all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit()))
+            });
+
+            if !valid {
+                self.report(
+                    table_at.clone(),
+                    format!(
+                        "package \"{name}\": `min_version` must be a dotted version like \"8.2\""
+                    ),
+                );
+            }
+        }
+
+        if let Some(path) = table.get("path").filter(|_| kind == "path") {
+            let expanded = path.as_str().and_then(|path| expand_home(path).ok());
+
+            match expanded {
+                Some(path) if !Path::new(&path).is_absolute() => self.report(
+                    table_at.clone(),
+                    format!("package \"{name}\": `path` must be absolute or start with `~`"),
+                ),
+                Some(path) if !Path::new(&path).is_dir() => self.report(
+                    table_at.clone(),
+                    format!("package \"{name}\": source directory {path} does not exist"),
+                ),
+                Some(_) => {}
+                None => self.report(
+                    table_at.clone(),
+                    format!("package \"{name}\": `path` must be a string"),
+                ),
+            }
+        }
+
+        for key in table.keys() {
+            if !required.contains(&key.as_str()) && !optional.contains(&key.as_str()) {
+                self.report(
+                    table_at.clone(),
+                    format!(
+                        "package \"{name}\": unexpected key `{key}` in `download` for download_type = \"{kind}\""
+                    ),
+                );
+            }
+        }
+    }
+
+    fn check_dependencies(
+        &mut self,
+        packages: &[toml::Value],
+        locations: &[(String, PackageLocations)],
+    ) {
+        let names: Vec<_> = packages
+            .iter()
+            .filter_map(|package| package.get("name")?.as_str())
+            .collect();
+
+        for package in packages {
+            let Some(name) = package.get("name").and_then(|name| name.as_str()) else {
+                continue;
+            };
+            let depends_on = package
+                .get("depends_on")
+                .and_then(|deps| deps.as_array())
+                .into_iter()
+                .flatten()
+                .filter_map(|dep| dep.as_str());
+
+            for dep in depends_on {
+                let message = if dep == name {
+                    format!("package \"{name}\": depends on itself")
+                } else if !names.contains(&dep) {
+                    format!("package \"{name}\": depends on unknown package \"{dep}\"")
+                } else {
+                    continue;
+                };
+                let location = locations
+                    .iter()
+                    .find(|(n, _)| n == name)
+                    .and_then(|(_, locations)| locations.entry.clone())
+                    .unwrap_or_else(|| self.top());
+                self.report(location, message);
+            }
+        }
+    }
+
+    fn check_pack_types(&mut self, found: &[(PackType, Location)]) {
+        for required in REQUIRED_PACK_TYPES {
+            let locations: Vec<_> = found
+                .iter()
+                .filter(|(pack_type, _)| pack_type == required)
+                .map(|(_, location)| location.clone())
+                .collect();
+
+            match locations.as_slice() {
+                [] => self.report(
+                    self.top(),
+                    format!(
+                        "no package with pack_type \"{}\"",
+                        pack_type_name(*required)
+                    ),
+                ),
+                [_] => {}
+                [_, duplicates @ ..] => {
+                    for location in duplicates {
+                        self.report(
+                            location.clone(),
+                            format!(
+                                "duplicate package with pack_type \"{}\"",
+                                pack_type_name(*required)
+                            ),
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Checks a config file (and the files it `extends`) for semantic problems that
+/// plain deserialization lets through
+pub fn validate_config_file<P>(path: P) -> Result<Vec<ConfigIssue>>
+where
+    P: AsRef<Path>,
+{
+    Ok(validate_config_sources(&read_config_chain(path)?))
+}
+
+/// Fails with [`ConfigIssues`] if the loaded inheritance chain of the config
+/// at `path` has any problem
+pub fn ensure_valid_config(path: &Path, sources: &[ConfigSource]) -> Result<()> {
+    let issues = validate_config_sources(sources);
+    if issues.is_empty() {
+        return Ok(());
+    }
+
+    Err(ConfigIssues {
+        path: path.to_path_buf(),
+        issues,
+    }
+    .into())
+}
+
+/// Same as [`validate_config_file`] for an already loaded inheritance chain (base-first)
+pub fn validate_config_sources(sources: &[ConfigSource]) -> Vec<ConfigIssue> {
+    let mut validator = Validator {
+        sources,
+        issues: Vec::new(),
+    };
+
+    if sources.is_empty() {
+        return validator.issues;
+    }
+
+    // First pass: parse every file on its own to learn where things are defined
+    let mut arch_at = None;
+    let mut sanitizers_at = None;
+    let mut locations: Vec<(String, PackageLocations)> = Vec::new();
+
+    for (index, source) in sources.iter().enumerate() {
+        let raw: RawConfig = match toml::from_str(&source.content) {
+            Ok(raw) => raw,
+            Err(err) => {
+                validator.report(
+                    (index, err.span().unwrap_or(0..0)),
+                    err.message().to_string(),
+                );
+                continue;
+            }
+        };
+
+        if let Some(arch) = &raw.arch {
+            arch_at = Some((index, arch.span()));
+        }
+        if let Some(sanitizers) = &raw.sanitizers {
+            sanitizers_at = Some((index, sanitizers.span()));
+        }
+
+        for package in &raw.packages {
+            let Some(name) = &package.get_ref().name else {
+                validator.report(
+                    (index, package.span()),
+                    "package without `name`".to_string(),
+                );
+                continue;
+            };
+
+            let position = match locations.iter().position(|(n, _)| n == name.get_ref()) {
+                Some(position) => position,
+                None => {
+                    locations.push((name.get_ref().clone(), PackageLocations::default()));
+                    locations.len() - 1
+                }
+            };
+
+            let at = |span: Option<Range<usize>>| span.map(|span| (index, span));
+            let raw_package = package.get_ref();
+            let entry = &mut locations[position].1;
+            entry.entry = Some((index, package.span()));
+            entry.pack_type =
+                at(raw_package.pack_type.as_ref().map(|v| v.span())).or(entry.pack_type.take());
+            entry.download_type = at(raw_package.download_type.as_ref().map(|v| v.span()))
+                .or(entry.download_type.take());
+            entry.download =
+                at(raw_package.download.as_ref().map(|v| v.span())).or(entry.download.take());
+        }
+    }
+
+    if !validator.issues.is_empty() {
+        return validator.issues;
+    }
+
+    // Second pass: check the merged result
+    let mut merged = match merge_config_sources(sources) {
+        Ok(merged) => merged,
+        Err(err) => {
+            validator.report(validator.top(), err.to_string());
+            return validator.issues;
+        }
+    };
+
+    validator.check_arch(merged.get("arch").and_then(|arch| arch.as_str()), arch_at);
+
+    validator.check_sanitizers(&merged, sanitizers_at);
+
+    for err in interpolate_packages(&mut merged) {
+        let location = locations
+            .iter()
+            .find(|(name, _)| *name == err.package)
+            .and_then(|(_, locations)| locations.entry.clone())
+            .unwrap_or_else(|| validator.top());
+        validator.report(location, err.to_string());
+    }
+
+    let packages = merged
+        .get("packages")
+        .and_then(|packages| packages.as_array())
+        .cloned()
+        .unwrap_or_default();
+
+    let mut found = Vec::new();
+    for package in packages.iter().filter_map(|package| package.as_table()) {
+        let Some(name) = package.get("name").and_then(|name| name.as_str()) else {
+            continue;
+        };
+        let package_locations = locations
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, locations)| locations);
+        let default_locations = PackageLocations::default();
+        let package_locations = package_locations.unwrap_or(&default_locations);
+
+        if let Some(pack_type) = validator.check_package(name, package, package_locations) {
+            let location = package_locations
+                .entry
+                .clone()
+                .unwrap_or_else(|| validator.top());
+            found.push((pack_type, location));
+        }
+    }
+
+    validator.check_pack_types(&found);
+    validator.check_dependencies(&packages, &locations);
+
+    // Only surface the strict parse errors once the friendlier checks pass, so
+    // the same mistake is not reported twice
+    if validator.issues.is_empty() {
+        if let [source] = sources {
+            if let Err(err) = toml::from_str::<Config>(&source.content) {
+                validator.report((0, err.span().unwrap_or(0..0)), err.message().to_string());
+            }
+        } else if let Err(err) = toml::Value::Table(merged).try_into::<Config>() {
+            validator.report(validator.top(), err.message().to_string());
+        }
+    }
+
+    validator.issues
+}
+
+fn pack_type_name(pack_type: PackType) -> String {
+    pack_type.to_string().to_lowercase()
+}
+
+fn line_col(content: &str, offset: usize) -> (usize, usize) {
+    let offset = offset.min(content.len());
+    let before = &content[..offset];
+    let line = before.matches('\n').count() + 1;
+    let column = before.rfind('\n').map_or(offset, |nl| offset - nl - 1) + 1;
+
+    (line, column)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PACKAGES: &str = r#"
+[[packages]]
+name = "toolchain"
+pack_type = "toolchain"
+version = "1"
+download_type = "file"
+download = { url = "https://example.com/toolchain.tar.xz" }
+
+[[packages]]
+name = "qemu"
+pack_type = "qemu"
+version = "9.2.0"
+download_type = "file"
+download = { url = "https://example.com/qemu-9.2.0.tar.xz" }
+
+[[packages]]
+name = "opensbi"
+pack_type = "opensbi"
+version = "1"
+download_type = "git"
+download = { url = "https://example.com/opensbi.git", branch = "master" }
+
+[[packages]]
+name = "linux"
+pack_type = "linux"
+version = "1"
+download_type = "git"
+download = { url = "https://example.com/linux.git", tag = "v6.12" }
+
+[[packages]]
+name = "buildroot"
+pack_type = "buildroot"
+version = "1"
+download_type = "git"
+download = { url = "https://example.com/buildroot.git", branch = "master" }
+"#;
+
+    fn source(name: &str, content: &str) -> ConfigSource {
+        ConfigSource {
+            path: PathBuf::from(name),
+            content: content.to_string(),
+        }
+    }
+
+    fn config(header: &str) -> String {
+        format!("{header}\n{PACKAGES}")
+    }
+
+    fn messages(sources: &[ConfigSource]) -> Vec<String> {
+        validate_config_sources(sources)
+            .into_iter()
+            .map(|issue| issue.message)
+            .collect()
+    }
+
+    #[test]
+    fn accepts_a_complete_config() {
+        let content = config("name = \"ok\"\narch = \"riscv64\"\nbuild_type = \"debug\"");
+
+        assert!(messages(&[source("ok.toml", &content)]).is_empty());
+    }
+
+    #[test]
+    fn reports_an_unknown_arch_where_it_is_set() {
+        let content = config("name = \"bad\"\narch = \"arm\"\nbuild_type = \"debug\"");
+        let issues = validate_config_sources(&[source("bad.toml", &content)]);
+
+        assert_eq!(issues.len(), 1);
+        assert_eq!((issues[0].line, issues[0].column), (2, 8));
+        assert!(issues[0].message.starts_with("unknown arch \"arm\""));
+    }
+
+    #[test]
+    fn rejects_arches_without_a_buildroot_defconfig() {
+        let content = config("name = \"rv32\"\narch = \"riscv32\"\nbuild_type = \"debug\"");
+
+        assert_eq!(
+            messages(&[source("rv32.toml", &content)]),
+            ["unknown arch \"riscv32\" (supported: riscv64)"]
+        );
+    }
+
+    #[test]
+    fn reports_missing_and_duplicate_pack_types() {
+        let content = config("name = \"x\"\narch = \"riscv64\"\nbuild_type = \"debug\"")
+            .replace("pack_type = \"opensbi\"", "pack_type = \"qemu\"");
+
+        let messages = messages(&[source("x.toml", &content)]);
+        assert!(messages.contains(&"no package with pack_type \"opensbi\"".to_string()));
+        assert!(messages.contains(&"duplicate package with pack_type \"qemu\"".to_string()));
+    }
+
+    #[test]
+    fn reports_a_git_download_without_a_ref() {
+        let content = config("name = \"x\"\narch = \"riscv64\"\nbuild_type = \"debug\"")
+            .replace(", branch = \"master\" }", " }");
+
+        let messages = messages(&[source("x.toml", &content)]);
+        assert!(messages
+            .iter()
+            .any(|message| message.starts_with("package \"opensbi\": download_type = \"git\"")));
+    }
+
+    #[test]
+    fn reports_conflicting_sanitizers() {
+        let content = config(
+            "name = \"x\"\narch = \"riscv64\"\nbuild_type = \"debug\"\nsanitizers = [\"kasan\", \"kcsan\"]",
+        );
+
+        assert_eq!(
+            messages(&[source("x.toml", &content)]),
+            ["the kasan and kcsan sanitizers cannot be enabled together"]
+        );
+    }
+
+    #[test]
+    fn points_at_the_file_of_the_chain_that_broke_it() {
+        let base = config("name = \"base\"\narch = \"riscv64\"\nbuild_type = \"debug\"");
+        let child = "extends = \"base.toml\"\nname = \"child\"\narch = \"mips\"\n";
+
+        let issues =
+            validate_config_sources(&[source("base.toml", &base), source("child.toml", child)]);
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].file, PathBuf::from("child.toml"));
+        assert_eq!(issues[0].line, 3);
+    }
+}
@@ -10,8 +10,8 @@ gdb_work_dir = os.getenv("GDB_WORK_DIR")
 if gdb_work_dir is None:
     raise RuntimeError("GDB_WORK_DIR environment variable is not set")
 
-# Get the directory contains projects
-work_dir = os.path.join(gdb_work_dir, "builds")
+# Get the directory contains projects (one per build type)
+work_dir = os.getenv("GDB_BUILD_DIR") or os.path.join(gdb_work_dir, "builds", "debug")
 
 # ==============================================
 # Load symbols
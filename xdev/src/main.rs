@@ -1,7 +1,15 @@
-use clap::{Args, Parser, Subcommand};
+use clap::{ArgGroup, Args, Parser, Subcommand, ValueEnum};
 use color_eyre::Result;
-use crossdev::{config::*, crossdev::*, env::*, gdb::*, ssh::*, utils::*};
-use std::fs;
+use crossdev::{
+    cache::*, config::*, crossdev::*, env::*, gdb::*, lock::*, reporter::*, runner::*, ssh::*,
+    status::*, utils::*, validate::*,
+};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 /// Cross-platform setup script
 #[derive(Parser, Debug)]
@@ -12,26 +20,158 @@ use std::fs;
 pub struct Cli {
     #[command(subcommand)]
     command: Option<Commands>,
+
+    /// How to print progress: for people, or one JSON event per line
+    #[arg(long, global = true, value_enum, default_value_t = MessageFormat::Human)]
+    message_format: MessageFormat,
+
+    /// Directory of the download cache shared by every profile
+    /// (default: $CROSSDEV_CACHE_DIR or cache/ in the work dir)
+    #[arg(long, global = true, value_name = "DIR")]
+    cache_dir: Option<PathBuf>,
+
+    /// Fail every step that needs the network instead of trying it
+    #[arg(long, global = true)]
+    offline: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum MessageFormat {
+    Human,
+    Json,
 }
 
 #[derive(Debug, Subcommand)]
 enum Commands {
     Config(ConfigCmd),
     Setup(SubArgs),
+    Update(UpdateCmd),
+    Clean(CleanCmd),
+    Status(StatusCmd),
+    Cache(CacheCmd),
+    Bundle(BundleCmd),
     Run(RunCmd),
     Ssh,
     Gdb,
-    Toolchain,
-    Qemu,
-    Sbi,
-    Linux,
-    Buildroot,
+    Toolchain(DryRunArgs),
+    Qemu(DryRunArgs),
+    Sbi(DryRunArgs),
+    Linux(DryRunArgs),
+    Buildroot(DryRunArgs),
 }
 
 #[derive(Args, Debug)]
+#[command(args_conflicts_with_subcommands = true)]
 struct ConfigCmd {
+    #[command(subcommand)]
+    action: Option<ConfigAction>,
+
+    /// Config file to use as the active config
+    #[arg(value_name = "FILE")]
+    file: Option<PathBuf>,
+}
+
+#[derive(Debug, Subcommand)]
+enum ConfigAction {
+    /// Validate a config file (defaults to the active config)
+    Check(ConfigCheckCmd),
+    /// Print a config file (defaults to the active config)
+    Show(ConfigShowCmd),
+}
+
+#[derive(Args, Debug)]
+struct ConfigCheckCmd {
+    #[arg(value_name = "FILE")]
+    file: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+struct ConfigShowCmd {
+    /// Print the config merged with everything it extends
+    #[arg(long)]
+    resolved: bool,
+
     #[arg(value_name = "FILE")]
-    file: std::path::PathBuf,
+    file: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+struct UpdateCmd {
+    /// Name of the package in the config, every package without it
+    package: Option<String>,
+}
+
+#[derive(Args, Debug)]
+#[command(group(ArgGroup::new("target").required(true).args(["package", "all"])))]
+struct CleanCmd {
+    /// Name of the package in the config
+    package: Option<String>,
+
+    /// Clean every package
+    #[arg(long)]
+    all: bool,
+
+    /// Also delete the downloaded sources
+    #[arg(long)]
+    sources: bool,
+}
+
+#[derive(Args, Debug)]
+struct StatusCmd {
+    /// Print the status as JSON, e.g. for editor plugins and scripts
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Args, Debug)]
+struct CacheCmd {
+    #[command(subcommand)]
+    action: CacheAction,
+}
+
+#[derive(Debug, Subcommand)]
+enum CacheAction {
+    /// List the cached downloads
+    List(CacheListCmd),
+    /// Remove cached downloads that haven't been used for a while
+    Prune(CachePruneCmd),
+}
+
+#[derive(Args, Debug)]
+struct CacheListCmd {
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Args, Debug)]
+struct CachePruneCmd {
+    /// Remove downloads not used for this many days
+    #[arg(long, value_name = "DAYS", default_value_t = 30)]
+    older_than: u64,
+
+    /// Remove everything, including Buildroot's downloads
+    #[arg(long, conflicts_with = "older_than")]
+    all: bool,
+}
+
+#[derive(Args, Debug)]
+struct BundleCmd {
+    #[command(subcommand)]
+    action: BundleAction,
+}
+
+#[derive(Debug, Subcommand)]
+enum BundleAction {
+    /// Pack the profile's sources, cached archives and lockfile into a tar file
+    Export(BundleFileCmd),
+    /// Seed the profile from a bundle, e.g. on a machine without network
+    Import(BundleFileCmd),
+}
+
+#[derive(Args, Debug)]
+struct BundleFileCmd {
+    #[arg(value_name = "FILE")]
+    file: PathBuf,
 }
 
 #[derive(Args, Debug)]
@@ -43,6 +183,28 @@ struct RunCmd {
 #[derive(Args, Debug)]
 struct SubArgs {
     name: Option<String>,
+
+    /// Build independent packages concurrently, sharing this many make jobs
+    #[arg(short, long)]
+    jobs: Option<usize>,
+
+    /// Rerun every phase of this package even if it is up to date
+    #[arg(long, value_name = "PACKAGE")]
+    force: Vec<String>,
+
+    #[command(flatten)]
+    dry_run: DryRunArgs,
+}
+
+#[derive(Args, Debug)]
+struct DryRunArgs {
+    /// Print every command, directory and environment variable instead of running them
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Also write the dry run as a standalone bash script (implies --dry-run)
+    #[arg(long, value_name = "FILE")]
+    emit_script: Option<PathBuf>,
 }
 
 const CONFIG_FILE_NAME: &str = ".config.toml";
@@ -52,29 +214,152 @@ fn main() -> Result<()> {
     color_eyre::install()?;
 
     let cli = Cli::parse();
+    let reporter: Arc<dyn Reporter> = match cli.message_format {
+        MessageFormat::Human => Arc::new(EmojiReporter),
+        MessageFormat::Json => Arc::new(JsonReporter),
+    };
+
+    // Messages of xdev itself go through the same reporter as the setup's
+    reporting_to(&reporter.clone(), || run(cli, reporter))
+}
+
+fn run(cli: Cli, reporter: Arc<dyn Reporter>) -> Result<()> {
+    let json = cli.message_format == MessageFormat::Json;
     let root_dir = get_root_dir()?;
     let work_dir = get_work_dir()?;
     let config_path = format!("{work_dir}/{CONFIG_FILE_NAME}");
     let def_config_path = format!("{root_dir}/{DEFAULT_CONFIG_PATH}");
 
+    if let Some(cache_dir) = &cli.cache_dir {
+        set_cache_dir(cache_dir.to_str().unwrap())?;
+    }
+    if cli.offline {
+        enable_offline();
+    }
+
+    // The cache is shared by every profile, so no config is needed
+    if let Some(Commands::Cache(cache)) = &cli.command {
+        return match &cache.action {
+            CacheAction::List(list) => print_cache(list.json || json),
+            CacheAction::Prune(prune_cmd) => {
+                let older_than = (!prune_cmd.all).then_some(prune_cmd.older_than * 86400);
+                let removed = prune(older_than)?;
+                let size: u64 = removed.iter().map(|entry| entry.size).sum();
+                info(format!(
+                    "🧹 Removed {} cached download(s), {}",
+                    removed.len(),
+                    format_size(size)
+                ));
+                Ok(())
+            }
+        };
+    }
+
     if let Some(Commands::Config(cfg)) = &cli.command {
-        let filepath = cfg.file.to_str().unwrap();
-        println!("📋 Copying {filepath} to {config_path}");
-        fs::copy(filepath, &config_path)?;
+        match (&cfg.action, &cfg.file) {
+            (Some(ConfigAction::Check(check)), _) => {
+                let filepath =
+                    selected_config(check.file.as_ref(), &config_path, &def_config_path)?;
+                validate_config(&filepath)?;
+                info(format!("✅ {filepath} is valid"));
+            }
+            (Some(ConfigAction::Show(show)), _) => {
+                let filepath = selected_config(show.file.as_ref(), &config_path, &def_config_path)?;
+
+                let contents = if show.resolved {
+                    let mut resolved = resolve_config_file(&filepath)?;
+                    resolved.remove("extends");
+                    toml::to_string(&resolved)?
+                } else {
+                    fs::read_to_string(&filepath)?
+                };
+
+                if json {
+                    let table: toml::Table = toml::from_str(&contents)?;
+                    println!("{}", serde_json::to_string_pretty(&table)?);
+                } else {
+                    print!("{contents}");
+                }
+            }
+            (None, Some(file)) => {
+                let filepath = file.to_str().unwrap();
+                info(format!("📋 Copying {filepath} to {config_path}"));
+                fs::copy(filepath, &config_path)?;
+            }
+            (None, None) => {
+                info("No config file provided");
+            }
+        }
         return Ok(());
     }
 
     check_config_file(&config_path, &def_config_path)?;
 
     let config = read_config_from_file(&config_path)?;
-    let mut xdev = CrossDev::new(config)?;
+    load_lockfile(lockfile_path(&config_path))?;
+
+    // Reporting the status must not download or build anything
+    if let Some(Commands::Status(status)) = &cli.command {
+        let xdev = CrossDev::load_with_reporter(config, reporter)?;
+        return print_status(&xdev.status()?, status.json || json);
+    }
+
+    // Cleaning must not set up the toolchain it may be about to remove
+    if let Some(Commands::Clean(clean)) = &cli.command {
+        let xdev = CrossDev::load_with_reporter(config, reporter)?;
+        return Ok(xdev.clean(clean.package.as_deref(), clean.sources)?);
+    }
+
+    // Importing a bundle is what makes the first setup possible offline
+    if let Some(Commands::Bundle(bundle)) = &cli.command {
+        let xdev = CrossDev::load_with_reporter(config, reporter)?;
+        match &bundle.action {
+            BundleAction::Export(export) => {
+                xdev.export_bundle(export.file.to_str().unwrap())?;
+                info(format!("📝 Wrote {}", export.file.display()));
+            }
+            BundleAction::Import(import) => {
+                xdev.import_bundle(import.file.to_str().unwrap())?;
+                info(format!(
+                    "✅ Imported {}, run setup to build from it",
+                    import.file.display()
+                ));
+            }
+        }
+        return Ok(());
+    }
+
+    let dry_run = match &cli.command {
+        Some(Commands::Setup(args)) => Some(&args.dry_run),
+        Some(Commands::Toolchain(args))
+        | Some(Commands::Qemu(args))
+        | Some(Commands::Sbi(args))
+        | Some(Commands::Linux(args))
+        | Some(Commands::Buildroot(args)) => Some(args),
+        _ => None,
+    };
+    // The toolchain is set up along with `CrossDev`, so start recording first
+    if dry_run.is_some_and(|args| args.dry_run || args.emit_script.is_some()) {
+        enable_dry_run();
+    }
+
+    let mut xdev = CrossDev::with_reporter(config, reporter)?;
 
     match &cli.command {
-        Some(Commands::Config(cfg)) => {
+        Some(Commands::Config(_)) => {
+            panic!("Unreachable because it's handled above");
+        }
+        Some(Commands::Status(_))
+        | Some(Commands::Cache(_))
+        | Some(Commands::Bundle(_))
+        | Some(Commands::Clean(_)) => {
             panic!("Unreachable because it's handled above");
         }
-        Some(Commands::Setup(_)) => {
-            xdev.setup()?;
+        Some(Commands::Setup(args)) => {
+            xdev.setup(args.jobs, &args.force)?;
+        }
+        Some(Commands::Update(update)) => {
+            xdev.update(update.package.as_deref())?;
         }
         Some(Commands::Run(runcmd)) => {
             let extra_args = runcmd.debug.then(|| vec!["-s", "-S"]);
@@ -87,30 +372,162 @@ fn main() -> Result<()> {
         Some(Commands::Gdb) => {
             run_gdb()?;
         }
-        Some(Commands::Toolchain) => {
-            let package = xdev.get_package(PackType::Toolchain)?;
-            package.setup()?;
+        Some(Commands::Toolchain(_)) => {
+            xdev.setup_package(PackType::Toolchain)?;
         }
-        Some(Commands::Qemu) => {
-            let package = xdev.get_package(PackType::Qemu)?;
-            package.setup()?;
+        Some(Commands::Qemu(_)) => {
+            xdev.setup_package(PackType::Qemu)?;
         }
-        Some(Commands::Sbi) => {
-            let package = xdev.get_package(PackType::Opensbi)?;
-            package.setup()?;
+        Some(Commands::Sbi(_)) => {
+            xdev.setup_package(PackType::Opensbi)?;
         }
-        Some(Commands::Linux) => {
-            let package = xdev.get_package(PackType::Linux)?;
-            package.setup()?;
+        Some(Commands::Linux(_)) => {
+            xdev.setup_package(PackType::Linux)?;
         }
-        Some(Commands::Buildroot) => {
-            let package = xdev.get_package(PackType::Buildroot)?;
-            package.setup()?;
+        Some(Commands::Buildroot(_)) => {
+            xdev.setup_package(PackType::Buildroot)?;
         }
         None => {
-            println!("No command provided");
+            info("No command provided");
+        }
+    }
+
+    if let Some(script) = dry_run.and_then(|args| args.emit_script.as_ref()) {
+        emit_script(script)?;
+        info(format!("📝 Wrote {}", script.display()));
+    }
+
+    Ok(())
+}
+
+// Falls back to the active config, creating it from the default one if needed
+fn selected_config(
+    file: Option<&PathBuf>,
+    config_path: &str,
+    def_config_path: &str,
+) -> Result<String> {
+    match file {
+        Some(file) => Ok(file.to_str().unwrap().to_string()),
+        None => {
+            check_config_file(config_path, def_config_path)?;
+            Ok(config_path.to_string())
+        }
+    }
+}
+
+fn validate_config(path: &str) -> Result<()> {
+    ensure_valid_config(Path::new(path), &read_config_chain(path)?)
+}
+
+fn print_status(status: &Status, json: bool) -> Result<()> {
+    if json {
+        println!("{}", serde_json::to_string_pretty(status)?);
+        return Ok(());
+    }
+
+    let mark = |done: bool| if done { "✅" } else { "❌" };
+
+    println!("📁 Work dir:  {}", status.env.work_dir);
+    println!("📁 Image dir: {}", status.env.image_dir);
+
+    for package in &status.packages {
+        println!();
+        println!(
+            "📦 {} ({} {})",
+            package.name, package.pack_type, package.version
+        );
+        println!(
+            "   sources:   {} {}",
+            mark(package.downloaded),
+            package.source_dir
+        );
+
+        if let (Some(built), Some(bin_path)) = (package.built, &package.bin_path) {
+            let mut details = Vec::new();
+            if let Some(size) = package.bin_size {
+                details.push(format_size(size));
+            }
+            if let Some(last_build) = package.last_build {
+                details.push(format!(
+                    "built {} ago",
+                    format_age(seconds_since(last_build))
+                ));
+            }
+            let details = if details.is_empty() {
+                String::new()
+            } else {
+                format!(" ({})", details.join(", "))
+            };
+            println!("   built:     {} {bin_path}{details}", mark(built));
+        }
+
+        if let (Some(installed), Some(image_path)) = (package.installed, &package.image_path) {
+            println!("   installed: {} {image_path}", mark(installed));
+        }
+        if let Some(commit) = &package.commit {
+            println!("   commit:    {commit}");
+        }
+        if let Some(sha256) = &package.sha256 {
+            println!("   sha256:    {sha256}");
         }
     }
 
     Ok(())
 }
+
+fn print_cache(json: bool) -> Result<()> {
+    let entries = list_entries()?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&entries)?);
+        return Ok(());
+    }
+
+    println!("📁 Cache dir: {}", cache_dir()?);
+    for entry in &entries {
+        println!();
+        println!("📦 {}", entry.url);
+        println!("   path:      {}", entry.path);
+        println!("   size:      {}", format_size(entry.size));
+        println!("   sha256:    {}", entry.sha256);
+        println!(
+            "   last used: {} ago",
+            format_age(seconds_since(entry.last_used))
+        );
+    }
+
+    let size: u64 = entries.iter().map(|entry| entry.size).sum();
+    println!();
+    println!("{} download(s), {}", entries.len(), format_size(size));
+
+    Ok(())
+}
+
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{size:.1} {}", UNITS[unit])
+}
+
+fn format_age(seconds: u64) -> String {
+    match seconds {
+        0..60 => format!("{seconds}s"),
+        60..3600 => format!("{}m", seconds / 60),
+        3600..86400 => format!("{}h", seconds / 3600),
+        _ => format!("{}d", seconds / 86400),
+    }
+}
+
+fn seconds_since(timestamp: u64) -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs().saturating_sub(timestamp))
+        .unwrap_or_default()
+}